@@ -0,0 +1,1003 @@
+use super::config::{ActivityPubConfig, Config, WebConfig};
+use super::conversions::{get_art_url, get_text};
+use super::mpd::SongStatus;
+use super::queue::DurableQueue;
+use anyhow::{anyhow, bail, Result};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use base64::prelude::*;
+use futures_util::stream::{self, StreamExt};
+use log::*;
+use rand_core::OsRng;
+use reqwest::{Client, RequestBuilder, Url};
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{
+    DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
+};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::path::Path as FsPath;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const CONTEXT: &str = "https://www.w3.org/ns/activitystreams";
+const SECURITY_CONTEXT: &str = "https://w3id.org/security/v1";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+const ACTIVITY_JSON: &str = "application/activity+json";
+/// How far the `Date` of a signed request may be from our clock.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(12 * 60 * 60);
+/// How many inboxes are delivered to at once.
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Follower {
+    pub id: String,
+    pub inbox: String,
+    #[serde(default)]
+    pub shared_inbox: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Delivery {
+    inbox: String,
+    activity: Value,
+}
+
+/// Whether retrying a failed delivery can't help, like when the inbox is gone.
+fn is_permanent(err: &anyhow::Error) -> bool {
+    match err
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+    {
+        Some(status) => status.is_client_error() && !matches!(status.as_u16(), 408 | 429),
+        None => false,
+    }
+}
+
+/// Reads the actor's key, generating one on first run.
+async fn load_key(path: &FsPath) -> Result<RsaPrivateKey> {
+    match fs::read_to_string(path).await {
+        Ok(pem) => Ok(RsaPrivateKey::from_pkcs8_pem(&pem)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("generating actor key at {}", path.display());
+            let key =
+                tokio::task::spawn_blocking(|| RsaPrivateKey::new(&mut OsRng, 2048)).await??;
+            let pem = key.to_pkcs8_pem(LineEnding::LF)?;
+
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .await?;
+            file.write_all(pem.as_bytes()).await?;
+
+            Ok(key)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", BASE64_STANDARD.encode(Sha256::digest(body)))
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str> {
+    let value = headers
+        .get(name)
+        .ok_or_else(|| anyhow!("missing {} header", name))?;
+    Ok(value.to_str()?)
+}
+
+fn request_target(method: &str, path_and_query: &str) -> String {
+    format!(
+        "(request-target): {} {}",
+        method.to_lowercase(),
+        path_and_query
+    )
+}
+
+/// Splits a `Signature` header into its parameters.
+fn parse_signature(header: &str) -> HashMap<&str, &str> {
+    header
+        .split(',')
+        .filter_map(|param| {
+            let (name, value) = param.trim().split_once('=')?;
+            Some((name, value.trim_matches('"')))
+        })
+        .collect()
+}
+
+/// Our own actor, along with who follows it and what it has published.
+pub struct ActivityPub {
+    username: String,
+    display_name: Option<String>,
+    summary: Option<String>,
+    base: String,
+    authority: String,
+    key: SigningKey<Sha256>,
+    public_key_pem: String,
+    client: Client,
+    followers: Mutex<DurableQueue<Follower>>,
+    outbox: Mutex<DurableQueue<Value>>,
+    outbox_size: usize,
+    following: Mutex<DurableQueue<String>>,
+    deliveries: Mutex<DurableQueue<Delivery>>,
+}
+
+impl ActivityPub {
+    pub async fn open(config: &ActivityPubConfig, web_config: &WebConfig) -> Result<Self> {
+        let key = load_key(&config.key_path).await?;
+        Self::with_key(config, web_config, key).await
+    }
+
+    async fn with_key(
+        config: &ActivityPubConfig,
+        web_config: &WebConfig,
+        key: RsaPrivateKey,
+    ) -> Result<Self> {
+        let base = web_config.public_addr.trim_end_matches('/').to_string();
+        let url = Url::parse(&base)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("public_addr has no host"))?;
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        let public_key_pem = RsaPublicKey::from(&key).to_public_key_pem(LineEnding::LF)?;
+
+        Ok(Self {
+            username: config.username.clone(),
+            display_name: config.display_name.clone(),
+            summary: config.summary.clone(),
+            base,
+            authority,
+            key: SigningKey::new(key),
+            public_key_pem,
+            client: Client::builder().timeout(Duration::from_secs(10)).build()?,
+            followers: Mutex::new(DurableQueue::open(config.followers_path.clone()).await?),
+            outbox: Mutex::new(DurableQueue::open(config.outbox_path.clone()).await?),
+            outbox_size: config.outbox_size,
+            following: Mutex::new(DurableQueue::open(config.following_path.clone()).await?),
+            deliveries: Mutex::new(DurableQueue::open(config.deliveries_path.clone()).await?),
+        })
+    }
+
+    fn actor_id(&self) -> String {
+        format!("{}/actor", self.base)
+    }
+
+    fn followers_id(&self) -> String {
+        format!("{}/actor/followers", self.base)
+    }
+
+    /// Random rather than sequential, so IDs don't collide across restarts or clock changes.
+    fn new_id(&self, kind: &str) -> String {
+        format!("{}/{}/{}", self.base, kind, rand::random::<u64>())
+    }
+
+    fn actor(&self) -> Value {
+        let actor_id = self.actor_id();
+
+        json!({
+            "@context": [CONTEXT, SECURITY_CONTEXT],
+            "id": actor_id,
+            "type": "Service",
+            "preferredUsername": self.username,
+            "name": self.display_name.as_deref().unwrap_or(&self.username),
+            "summary": self.summary.as_deref().unwrap_or_default(),
+            "url": actor_id,
+            "inbox": format!("{}/inbox", actor_id),
+            "outbox": format!("{}/outbox", actor_id),
+            "followers": self.followers_id(),
+            "manuallyApprovesFollowers": false,
+            "publicKey": {
+                "id": format!("{}#main-key", actor_id),
+                "owner": actor_id,
+                "publicKeyPem": self.public_key_pem,
+            },
+        })
+    }
+
+    fn webfinger(&self, resource: &str) -> Option<Value> {
+        let subject = format!("acct:{}@{}", self.username, self.authority);
+        if resource != subject && resource != self.actor_id() {
+            return None;
+        }
+
+        Some(json!({
+            "subject": subject,
+            "aliases": [self.actor_id()],
+            "links": [{
+                "rel": "self",
+                "type": ACTIVITY_JSON,
+                "href": self.actor_id(),
+            }],
+        }))
+    }
+
+    async fn outbox_collection(&self) -> Value {
+        let outbox = self.outbox.lock().await;
+        let items: Vec<_> = outbox.items().iter().rev().collect();
+
+        json!({
+            "@context": CONTEXT,
+            "id": format!("{}/outbox", self.actor_id()),
+            "type": "OrderedCollection",
+            "totalItems": items.len(),
+            "orderedItems": items,
+        })
+    }
+
+    /// Only the number of followers, like most servers show.
+    async fn followers_collection(&self) -> Value {
+        json!({
+            "@context": CONTEXT,
+            "id": self.followers_id(),
+            "type": "OrderedCollection",
+            "totalItems": self.followers.lock().await.items().len(),
+        })
+    }
+
+    async fn activity(&self, id: &str) -> Option<Value> {
+        let outbox = self.outbox.lock().await;
+        outbox
+            .items()
+            .iter()
+            .find(|x| x["id"].as_str() == Some(id))
+            .cloned()
+    }
+
+    /// Adds the headers of a draft-cavage HTTP signature, which is what the fediverse speaks.
+    fn sign(
+        &self,
+        request: RequestBuilder,
+        method: &str,
+        url: &Url,
+        body: Option<&[u8]>,
+    ) -> RequestBuilder {
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let path_and_query = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        let mut headers = vec![
+            ("host", host),
+            ("date", httpdate::fmt_http_date(SystemTime::now())),
+        ];
+        if let Some(body) = body {
+            headers.push(("digest", digest(body)));
+        }
+
+        let mut names = vec!["(request-target)"];
+        let mut lines = vec![request_target(method, &path_and_query)];
+        for (name, value) in &headers {
+            names.push(*name);
+            lines.push(format!("{}: {}", name, value));
+        }
+
+        let signature = self.key.sign(lines.join("\n").as_bytes());
+        headers.push((
+            "signature",
+            format!(
+                r#"keyId="{}#main-key",algorithm="rsa-sha256",headers="{}",signature="{}""#,
+                self.actor_id(),
+                names.join(" "),
+                BASE64_STANDARD.encode(signature.to_bytes())
+            ),
+        ));
+
+        headers.into_iter().fold(request, |request, (name, value)| {
+            request.header(name, value)
+        })
+    }
+
+    /// Fetches an object, signing the request for servers that require it.
+    pub async fn fetch(&self, id: &str) -> Result<Value> {
+        let url = Url::parse(id)?;
+        let request = self
+            .client
+            .get(url.clone())
+            .header(header::ACCEPT, ACTIVITY_JSON);
+
+        let response = self.sign(request, "get", &url, None).send().await?;
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    pub async fn deliver(&self, inbox: &str, activity: &Value) -> Result<()> {
+        let url = Url::parse(inbox)?;
+        let body = serde_json::to_vec(activity)?;
+        let request = self
+            .client
+            .post(url.clone())
+            .header(header::CONTENT_TYPE, ACTIVITY_JSON)
+            .body(body.clone());
+
+        self.sign(request, "post", &url, Some(&body))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Delivers to several inboxes at once, returning the deliveries worth retrying.
+    async fn deliver_all(&self, deliveries: Vec<Delivery>) -> Vec<Delivery> {
+        stream::iter(deliveries)
+            .map(|delivery| async move {
+                match self.deliver(&delivery.inbox, &delivery.activity).await {
+                    Ok(()) => None,
+                    Err(err) if is_permanent(&err) => {
+                        warn!("giving up delivering to {}: {:#}", delivery.inbox, err);
+                        None
+                    }
+                    Err(err) => {
+                        warn!(
+                            "couldn't deliver to {}, will retry: {:#}",
+                            delivery.inbox, err
+                        );
+                        Some(delivery)
+                    }
+                }
+            })
+            .buffer_unordered(MAX_CONCURRENT_DELIVERIES)
+            .filter_map(|x| async move { x })
+            .collect()
+            .await
+    }
+
+    /// Delivers the activities, queueing the ones that failed for `retry_deliveries`.
+    async fn send(&self, deliveries: Vec<Delivery>) -> Result<()> {
+        let failed = self.deliver_all(deliveries).await;
+
+        let mut queue = self.deliveries.lock().await;
+        for delivery in failed {
+            queue.push(delivery).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn has_pending_deliveries(&self) -> bool {
+        !self.deliveries.lock().await.is_empty()
+    }
+
+    /// Tries the queued deliveries again, returning whether all of them went through.
+    pub async fn retry_deliveries(&self) -> Result<bool> {
+        // Taken out of the queue, so publishing doesn't wait on slow inboxes.
+        let deliveries = {
+            let mut queue = self.deliveries.lock().await;
+            let deliveries = queue.items().to_vec();
+            queue.remove_front(deliveries.len()).await?;
+            deliveries
+        };
+
+        self.send(deliveries).await?;
+        Ok(!self.has_pending_deliveries().await)
+    }
+
+    /// Checks the HTTP signature of an incoming request, returning the ID of the actor who
+    /// signed it.
+    async fn verify(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<String> {
+        let params = parse_signature(header_str(headers, "signature")?);
+        let key_id = *params
+            .get("keyId")
+            .ok_or_else(|| anyhow!("signature has no keyId"))?;
+        let signature = params
+            .get("signature")
+            .ok_or_else(|| anyhow!("signature has no signature"))?;
+        let signed_headers = params.get("headers").copied().unwrap_or("date");
+
+        for required in ["(request-target)", "date", "digest"] {
+            if !signed_headers.split(' ').any(|x| x == required) {
+                bail!("{} isn't signed", required);
+            }
+        }
+
+        if header_str(headers, "digest")? != digest(body) {
+            bail!("digest doesn't match body");
+        }
+
+        let date = httpdate::parse_http_date(header_str(headers, "date")?)?;
+        let now = SystemTime::now();
+        if date + MAX_CLOCK_SKEW < now || now + MAX_CLOCK_SKEW < date {
+            bail!("date is too far off");
+        }
+
+        let path_and_query = uri.path_and_query().map_or(uri.path(), |x| x.as_str());
+        let lines = signed_headers
+            .split(' ')
+            .map(|name| match name {
+                "(request-target)" => Ok(request_target(method.as_str(), path_and_query)),
+                name => Ok(format!("{}: {}", name, header_str(headers, name)?)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Key IDs are usually a fragment of the actor, but some servers serve keys on their own.
+        let document = self.fetch(key_id.split('#').next().unwrap()).await?;
+        let public_key = if document["publicKey"].is_object() {
+            &document["publicKey"]
+        } else {
+            &document
+        };
+
+        if public_key["id"].as_str() != Some(key_id) {
+            bail!("couldn't find key {}", key_id);
+        }
+        let owner = public_key["owner"]
+            .as_str()
+            .ok_or_else(|| anyhow!("key {} has no owner", key_id))?;
+        let pem = public_key["publicKeyPem"]
+            .as_str()
+            .ok_or_else(|| anyhow!("key {} has no PEM", key_id))?;
+
+        let public_key = RsaPublicKey::from_public_key_pem(pem)?;
+        let key = VerifyingKey::<Sha256>::new(public_key.clone());
+        let signature = Signature::try_from(&*BASE64_STANDARD.decode(signature)?)?;
+        key.verify(lines.join("\n").as_bytes(), &signature)?;
+
+        // Anyone can serve a key claiming to be someone's, so the owner has to claim it back.
+        if Url::parse(owner)?.origin() != Url::parse(key_id)?.origin() {
+            bail!("key {} can't belong to {}", key_id, owner);
+        }
+        if document["id"].as_str() != Some(owner) {
+            let owner_document = self.fetch(owner).await?;
+            let owner_key = &owner_document["publicKey"];
+            let owner_pem = owner_key["publicKeyPem"]
+                .as_str()
+                .ok_or_else(|| anyhow!("{} has no key", owner))?;
+
+            if owner_key["id"].as_str() != Some(key_id)
+                || RsaPublicKey::from_public_key_pem(owner_pem)? != public_key
+            {
+                bail!("{} doesn't claim key {}", owner, key_id);
+            }
+        }
+
+        Ok(owner.to_string())
+    }
+
+    async fn receive(
+        self: &Arc<Self>,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<()> {
+        let signer = self.verify(method, uri, headers, body).await?;
+        let activity: Value = serde_json::from_slice(body)?;
+
+        let actor = activity["actor"]
+            .as_str()
+            .ok_or_else(|| anyhow!("activity has no actor"))?;
+        if actor != signer {
+            bail!("{} signed an activity by {}", signer, actor);
+        }
+
+        match activity["type"].as_str() {
+            Some("Follow") if activity["object"].as_str() == Some(self.actor_id().as_str()) => {
+                let document = self.fetch(actor).await?;
+                let inbox = document["inbox"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("{} has no inbox", actor))?
+                    .to_string();
+                let follower = Follower {
+                    id: actor.to_string(),
+                    inbox: inbox.clone(),
+                    shared_inbox: document["endpoints"]["sharedInbox"]
+                        .as_str()
+                        .map(str::to_string),
+                };
+
+                let mut followers = self.followers.lock().await;
+                followers.retain(|x| x.id != actor).await?;
+                followers.push(follower).await?;
+                info!("{} followed", actor);
+
+                let accept = json!({
+                    "@context": CONTEXT,
+                    "id": self.new_id("accepts"),
+                    "type": "Accept",
+                    "actor": self.actor_id(),
+                    "object": activity,
+                });
+
+                // Delivered in the background, so the follow request gets its response first.
+                let activitypub = self.clone();
+                let delivery = Delivery {
+                    inbox,
+                    activity: accept,
+                };
+                tokio::spawn(async move {
+                    if let Err(err) = activitypub.send(vec![delivery]).await {
+                        warn!("couldn't accept follow: {}", err);
+                    }
+                });
+            }
+            Some("Undo") if activity["object"]["type"].as_str() == Some("Follow") => {
+                let mut followers = self.followers.lock().await;
+                followers.retain(|x| x.id != actor).await?;
+                info!("{} unfollowed", actor);
+            }
+            Some("Accept") => info!("{} accepted our follow", actor),
+            other => debug!("ignoring {:?} from {}", other, actor),
+        }
+
+        Ok(())
+    }
+
+    /// Whether a follow request was already sent to the actor.
+    pub async fn is_following(&self, id: &str) -> bool {
+        self.following.lock().await.items().iter().any(|x| x == id)
+    }
+
+    pub async fn follow(&self, id: &str) -> Result<()> {
+        let document = self.fetch(id).await?;
+        let inbox = document["inbox"]
+            .as_str()
+            .ok_or_else(|| anyhow!("{} has no inbox", id))?;
+
+        let follow = json!({
+            "@context": CONTEXT,
+            "id": self.new_id("follows"),
+            "type": "Follow",
+            "actor": self.actor_id(),
+            "object": id,
+        });
+        self.deliver(inbox, &follow).await?;
+
+        self.following.lock().await.push(id.to_string()).await
+    }
+
+    /// Builds a `Listen` for the current song, if there is one.
+    pub fn listen(&self, song_status: &SongStatus, config: &Config) -> Option<Value> {
+        let song = song_status.song.as_ref()?;
+
+        let mut object = json!({
+            "type": "Audio",
+            "name": get_text(song_status)?,
+        });
+        if let Some(duration) = song.duration {
+            object["duration"] = Value::from(format!("PT{}S", duration.as_secs()));
+        }
+        if let Some(url) = get_art_url(song_status, config) {
+            object["image"] = json!({ "type": "Image", "url": url });
+        }
+
+        Some(json!({
+            "@context": CONTEXT,
+            "id": self.new_id("listens"),
+            "type": "Listen",
+            "actor": self.actor_id(),
+            "published": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "to": [PUBLIC],
+            "cc": [self.followers_id()],
+            "object": object,
+        }))
+    }
+
+    /// Adds the activity to the outbox and delivers it to every follower, queueing the deliveries
+    /// that failed.
+    pub async fn publish(&self, activity: Value) -> Result<()> {
+        {
+            let mut outbox = self.outbox.lock().await;
+            outbox.push(activity.clone()).await?;
+
+            let excess = outbox.items().len().saturating_sub(self.outbox_size);
+            if excess > 0 {
+                outbox.remove_front(excess).await?;
+            }
+        }
+
+        // Servers with a shared inbox only need it once for all their users.
+        let inboxes: BTreeSet<_> = self
+            .followers
+            .lock()
+            .await
+            .items()
+            .iter()
+            .map(|x| x.shared_inbox.clone().unwrap_or_else(|| x.inbox.clone()))
+            .collect();
+
+        let deliveries = inboxes
+            .into_iter()
+            .map(|inbox| Delivery {
+                inbox,
+                activity: activity.clone(),
+            })
+            .collect();
+        self.send(deliveries).await
+    }
+}
+
+fn activity_json(value: Value) -> Response {
+    ([(header::CONTENT_TYPE, ACTIVITY_JSON)], value.to_string()).into_response()
+}
+
+async fn webfinger(
+    State(activitypub): State<Arc<ActivityPub>>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let resource = query.get("resource").map_or("", String::as_str);
+
+    match activitypub.webfinger(resource) {
+        Some(jrd) => (
+            [(header::CONTENT_TYPE, "application/jrd+json")],
+            jrd.to_string(),
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+async fn actor(State(activitypub): State<Arc<ActivityPub>>) -> Response {
+    activity_json(activitypub.actor())
+}
+
+async fn inbox(
+    State(activitypub): State<Arc<ActivityPub>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    match activitypub.receive(&method, &uri, &headers, &body).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(err) => {
+            debug!("rejected activity: {}", err);
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+async fn outbox(State(activitypub): State<Arc<ActivityPub>>) -> Response {
+    activity_json(activitypub.outbox_collection().await)
+}
+
+async fn followers(State(activitypub): State<Arc<ActivityPub>>) -> Response {
+    activity_json(activitypub.followers_collection().await)
+}
+
+async fn listen(State(activitypub): State<Arc<ActivityPub>>, Path(id): Path<u64>) -> Response {
+    let id = format!("{}/listens/{}", activitypub.base, id);
+
+    match activitypub.activity(&id).await {
+        Some(activity) => activity_json(activity),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+pub fn routes(activitypub: Arc<ActivityPub>) -> Router {
+    Router::new()
+        .route("/.well-known/webfinger", get(webfinger))
+        .route("/actor", get(actor))
+        .route("/actor/inbox", post(inbox))
+        .route("/actor/outbox", get(outbox))
+        .route("/actor/followers", get(followers))
+        .route("/listens/:id", get(listen))
+        .with_state(activitypub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::mpd::test_util::playing;
+    use crate::mpd::Change;
+    use crate::util::test_util::mock_server;
+    use axum::body::{self, Body};
+    use axum::extract::Request;
+    use axum::middleware::{self, Next};
+    use std::env::temp_dir;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::net::TcpListener;
+    use tokio::time::sleep;
+
+    /// The activities an actor's inbox accepted, and whether it's refusing deliveries.
+    #[derive(Default)]
+    struct Inbox {
+        activities: std::sync::Mutex<Vec<Value>>,
+        down: AtomicBool,
+    }
+
+    impl Inbox {
+        /// Waits for an activity of the given type to arrive, as some are delivered in the
+        /// background.
+        async fn wait_for(&self, kind: &str) -> Value {
+            for _ in 0..500 {
+                let activity = {
+                    let activities = self.activities.lock().unwrap();
+                    activities.iter().find(|x| x["type"] == kind).cloned()
+                };
+                if let Some(activity) = activity {
+                    return activity;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+            panic!("no {} arrived", kind);
+        }
+    }
+
+    async fn record(inbox: Arc<Inbox>, request: Request, next: Next) -> Response {
+        if request.uri().path() != "/actor/inbox" {
+            return next.run(request).await;
+        }
+        if inbox.down.load(Ordering::SeqCst) {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+
+        let (parts, body) = request.into_parts();
+        let body = body::to_bytes(body, usize::MAX).await.unwrap();
+        let response = next
+            .run(Request::from_parts(parts, Body::from(body.clone())))
+            .await;
+        if response.status().is_success() {
+            let activity = serde_json::from_slice(&body).unwrap();
+            inbox.activities.lock().unwrap().push(activity);
+        }
+        response
+    }
+
+    /// An actor served on a free local port, with its state in fresh temporary files.
+    async fn actor(name: &str) -> (Arc<ActivityPub>, Arc<Inbox>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let path = |kind: &str| {
+            let path = temp_dir().join(format!(
+                "mpdiscord-{}-{}-{}.json",
+                name,
+                kind,
+                process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            path
+        };
+        let config = test_util::config(&format!(
+            "[web]\nlisten_addr = \"{addr}\"\npublic_addr = \"http://{addr}\"\n\
+             [activitypub]\nfollowers_path = {:?}\noutbox_path = {:?}\n\
+             following_path = {:?}\ndeliveries_path = {:?}\n",
+            path("followers"),
+            path("outbox"),
+            path("following"),
+            path("deliveries"),
+            addr = addr
+        ));
+
+        // Smaller than a real key, as generating one is slow without optimizations.
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let activitypub = ActivityPub::with_key(
+            config.activitypub.as_ref().unwrap(),
+            config.web.as_ref().unwrap(),
+            key,
+        )
+        .await
+        .unwrap();
+
+        let activitypub = Arc::new(activitypub);
+        let inbox = Arc::new(Inbox::default());
+        let recorder = inbox.clone();
+        let app = routes(activitypub.clone()).layer(middleware::from_fn(move |request, next| {
+            record(recorder.clone(), request, next)
+        }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (activitypub, inbox)
+    }
+
+    async fn is_follower(activitypub: &ActivityPub, id: &str) -> bool {
+        let followers = activitypub.followers.lock().await;
+        followers.items().iter().any(|x| x.id == id)
+    }
+
+    #[test]
+    fn signature_params() {
+        let params = parse_signature(
+            r#"keyId="https://example.com/actor#main-key", algorithm="rsa-sha256",headers="(request-target) host date",signature="YWJj=""#,
+        );
+
+        assert_eq!(params["keyId"], "https://example.com/actor#main-key");
+        assert_eq!(params["algorithm"], "rsa-sha256");
+        assert_eq!(params["headers"], "(request-target) host date");
+        assert_eq!(params["signature"], "YWJj=");
+    }
+
+    #[tokio::test]
+    async fn webfinger_resources() {
+        let (activitypub, _) = actor("webfinger").await;
+        let subject = format!("acct:nowplaying@{}", activitypub.authority);
+
+        let jrd = activitypub.webfinger(&subject).unwrap();
+        assert_eq!(jrd["subject"], subject);
+        assert_eq!(jrd["links"][0]["href"], activitypub.actor_id());
+
+        assert!(activitypub.webfinger(&activitypub.actor_id()).is_some());
+        assert!(activitypub
+            .webfinger(&format!("acct:someone@{}", activitypub.authority))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn signed_follow_is_accepted() {
+        let (activitypub, _) = actor("follow").await;
+        let follow = json!({
+            "@context": CONTEXT,
+            "id": activitypub.new_id("follows"),
+            "type": "Follow",
+            "actor": activitypub.actor_id(),
+            "object": activitypub.actor_id(),
+        });
+
+        // The actor follows itself, so the signature is checked against the key it serves.
+        let inbox = format!("{}/inbox", activitypub.actor_id());
+        activitypub.deliver(&inbox, &follow).await.unwrap();
+        assert_eq!(activitypub.followers_collection().await["totalItems"], 1);
+    }
+
+    #[tokio::test]
+    async fn tampered_body_is_rejected() {
+        let (activitypub, _) = actor("tampered").await;
+        let url = Url::parse(&format!("{}/inbox", activitypub.actor_id())).unwrap();
+        let request = activitypub.client.post(url.clone());
+        let request = activitypub
+            .sign(request, "post", &url, Some(b"{}"))
+            .build()
+            .unwrap();
+
+        let uri: Uri = url.path().parse().unwrap();
+        let result = activitypub
+            .verify(
+                &Method::POST,
+                &uri,
+                request.headers(),
+                br#"{"type":"Delete"}"#,
+            )
+            .await;
+        assert!(result.is_err());
+
+        let signer = activitypub
+            .verify(&Method::POST, &uri, request.headers(), b"{}")
+            .await
+            .unwrap();
+        assert_eq!(signer, activitypub.actor_id());
+    }
+
+    #[tokio::test]
+    async fn follow_requests_are_remembered() {
+        let (activitypub, _) = actor("following").await;
+        let id = activitypub.actor_id();
+
+        assert!(!activitypub.is_following(&id).await);
+        activitypub.follow(&id).await.unwrap();
+        assert!(activitypub.is_following(&id).await);
+    }
+
+    #[tokio::test]
+    async fn forged_key_owner_is_rejected() {
+        let (alice, _) = actor("forger").await;
+        let (bob, _) = actor("victim").await;
+
+        // Alice's key, served on a host of her own, claiming to belong to someone else.
+        let pem = alice.public_key_pem.clone();
+        let owner = Arc::new(std::sync::Mutex::new(bob.actor_id()));
+        let claimed = owner.clone();
+        let base = mock_server(Router::new().route(
+            "/*path",
+            get(move |uri: Uri, headers: HeaderMap| async move {
+                let base = format!("http://{}", header_str(&headers, "host").unwrap());
+                let body = match uri.path() {
+                    "/key" => json!({
+                        "id": format!("{}/key", base),
+                        "owner": *claimed.lock().unwrap(),
+                        "publicKeyPem": pem,
+                    }),
+                    _ => json!({
+                        "id": format!("{}/victim", base),
+                        "publicKey": {
+                            "id": format!("{}/victim#main-key", base),
+                            "owner": format!("{}/victim", base),
+                            "publicKeyPem": "",
+                        },
+                    }),
+                };
+                activity_json(body)
+            }),
+        ))
+        .await;
+
+        // The key ID isn't covered by the signature, so it can be swapped for the forged one.
+        let url = Url::parse(&format!("{}/inbox", bob.actor_id())).unwrap();
+        let request = alice.client.post(url.clone());
+        let mut request = alice
+            .sign(request, "post", &url, Some(b"{}"))
+            .build()
+            .unwrap();
+        let signature = request.headers()["signature"].to_str().unwrap().replace(
+            &format!("{}#main-key", alice.actor_id()),
+            &format!("{}/key", base),
+        );
+        request
+            .headers_mut()
+            .insert("signature", signature.parse().unwrap());
+
+        let uri: Uri = url.path().parse().unwrap();
+        let result = bob
+            .verify(&Method::POST, &uri, request.headers(), b"{}")
+            .await;
+        assert!(result.is_err());
+
+        // Even on the key's own host, the owner has to list the key as its own.
+        *owner.lock().unwrap() = format!("{}/victim", base);
+        let result = bob
+            .verify(&Method::POST, &uri, request.headers(), b"{}")
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn follows_another_actor() {
+        let (alice, alice_inbox) = actor("alice").await;
+        let (bob, _) = actor("bob").await;
+
+        alice.follow(&bob.actor_id()).await.unwrap();
+        assert!(is_follower(&bob, &alice.actor_id()).await);
+
+        let accept = alice_inbox.wait_for("Accept").await;
+        assert_eq!(accept["actor"], bob.actor_id());
+        assert_eq!(accept["object"]["actor"], alice.actor_id());
+
+        let config = test_util::config("");
+        let song_status =
+            playing(1, "Title", 0.0, SystemTime::now()).with_change(Change::TrackChanged);
+        let listen = bob.listen(&song_status, &config).unwrap();
+        bob.publish(listen.clone()).await.unwrap();
+        assert_eq!(alice_inbox.wait_for("Listen").await, listen);
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried() {
+        let (alice, alice_inbox) = actor("retrier").await;
+        let (bob, _) = actor("retried").await;
+        alice.follow(&bob.actor_id()).await.unwrap();
+        alice_inbox.wait_for("Accept").await;
+
+        alice_inbox.down.store(true, Ordering::SeqCst);
+        let config = test_util::config("");
+        let song_status =
+            playing(1, "Title", 0.0, SystemTime::now()).with_change(Change::TrackChanged);
+        bob.publish(bob.listen(&song_status, &config).unwrap())
+            .await
+            .unwrap();
+        assert!(bob.has_pending_deliveries().await);
+        assert!(!bob.retry_deliveries().await.unwrap());
+        assert!(bob.has_pending_deliveries().await);
+
+        alice_inbox.down.store(false, Ordering::SeqCst);
+        assert!(bob.retry_deliveries().await.unwrap());
+        assert!(!bob.has_pending_deliveries().await);
+        alice_inbox.wait_for("Listen").await;
+    }
+}
@@ -7,13 +7,14 @@ use axum::Router;
 use bytes::BytesMut;
 use image::imageops::FilterType;
 use image::io::Reader as ImageReader;
-use image::ImageFormat;
+use image::{DynamicImage, ImageFormat};
 use mpd_client::commands::SongId;
 use std::fmt::Display;
 use std::io::Cursor;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 
+use crate::activitypub::{self, ActivityPub};
 use crate::config::WebConfig;
 use crate::mpd::Mpd;
 
@@ -21,6 +22,28 @@ fn err(x: impl Display) -> impl IntoResponse {
     (StatusCode::INTERNAL_SERVER_ERROR, x.to_string())
 }
 
+/// Opens cover art in whatever format MPD says it is, or otherwise seems to be.
+pub fn art_reader<'a>(data: &'a [u8], mime: Option<&str>) -> Result<ImageReader<Cursor<&'a [u8]>>> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+
+    if let Some(format) = mime.and_then(ImageFormat::from_mime_type) {
+        reader.set_format(format);
+    } else {
+        reader = reader.with_guessed_format()?;
+    }
+
+    Ok(reader)
+}
+
+/// Shrinks covers that are larger than anything displaying them needs.
+pub fn shrink_art(image: DynamicImage) -> DynamicImage {
+    if image.width() > 1024 || image.height() > 1024 {
+        image.resize(1000, 1000, FilterType::CatmullRom)
+    } else {
+        image
+    }
+}
+
 async fn art(
     Path(song_id): Path<u64>,
     State(mpd): State<Arc<Mpd>>,
@@ -29,23 +52,18 @@ async fn art(
 
     match mpd.song_art(song_id).await {
         Ok(Some((data, mime))) => {
-            let mut reader = ImageReader::new(Cursor::new(&data));
-
-            if let Some(format) = mime.and_then(ImageFormat::from_mime_type) {
-                reader.set_format(format);
-            } else {
-                reader = reader.with_guessed_format().map_err(err)?;
-            }
+            let reader = art_reader(&data, mime.as_deref()).map_err(err)?;
 
             if let Some(format) = reader.format() {
                 let image = reader.decode().map_err(err)?;
+                let (width, height) = (image.width(), image.height());
 
                 let mut headers = HeaderMap::new();
                 let mime = format.to_mime_type().parse().map_err(err)?;
                 headers.insert(header::CONTENT_TYPE, mime);
 
-                if image.width() > 1024 || image.height() > 1024 {
-                    let resized = image.resize(1000, 1000, FilterType::CatmullRom);
+                let resized = shrink_art(image);
+                if (resized.width(), resized.height()) != (width, height) {
                     let mut writer = Cursor::new(vec![]);
                     resized.write_to(&mut writer, format).map_err(err)?;
                     let data = BytesMut::from(&*writer.into_inner());
@@ -66,11 +84,19 @@ async fn art(
     }
 }
 
-pub async fn serve(web_config: &WebConfig, mpd: Arc<Mpd>) -> Result<!> {
-    let app = Router::new()
+pub async fn serve(
+    web_config: &WebConfig,
+    mpd: Arc<Mpd>,
+    activitypub: Option<Arc<ActivityPub>>,
+) -> Result<!> {
+    let mut app = Router::new()
         .route("/art/:song_id", get(art))
         .with_state(mpd);
 
+    if let Some(activitypub) = activitypub {
+        app = app.merge(activitypub::routes(activitypub));
+    }
+
     let listener = TcpListener::bind(web_config.listen_addr).await?;
 
     axum::serve(listener, app).await?;
@@ -0,0 +1,407 @@
+use super::art_server::{art_reader, shrink_art};
+use super::config::Visibility;
+use super::profile::{Field, Profile, ProfileBackend};
+use anyhow::{bail, Result};
+use image::ImageFormat;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::Cursor;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+const PROFILE_COLLECTION: &str = "app.bsky.actor.profile";
+const POST_COLLECTION: &str = "app.bsky.feed.post";
+
+/// Limits on the description and post text, which Bluesky counts in graphemes. Counting
+/// characters instead errs on the safe side.
+const MAX_DESCRIPTION: usize = 256;
+const MAX_POST: usize = 300;
+/// Largest image blob that can be embedded in a post.
+const MAX_IMAGE_SIZE: usize = 1_000_000;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    access_jwt: String,
+    did: String,
+    handle: String,
+}
+
+#[derive(Serialize)]
+struct CreateSession<'a> {
+    identifier: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+struct XrpcError {
+    error: String,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+struct ProfileRecord {
+    cid: Option<String>,
+    value: Value,
+}
+
+#[derive(Deserialize)]
+struct WrittenRecord {
+    cid: String,
+}
+
+#[derive(Deserialize)]
+struct UploadedBlob {
+    blob: Value,
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+/// Links every `#hashtag` in the text, since Bluesky only treats them as tags with a facet.
+fn hashtag_facets(text: &str) -> Vec<Value> {
+    let mut facets = vec![];
+    let mut offset = 0;
+
+    for word in text.split_inclusive(char::is_whitespace) {
+        let tag = word.trim_end();
+        if tag.len() > 1 && tag.starts_with('#') {
+            facets.push(json!({
+                "index": { "byteStart": offset, "byteEnd": offset + tag.len() },
+                "features": [{ "$type": "app.bsky.richtext.facet#tag", "tag": &tag[1..] }],
+            }));
+        }
+        offset += word.len();
+    }
+
+    facets
+}
+
+/// Re-encodes covers that are too big to be embedded as a JPEG.
+fn fit_image(data: Vec<u8>, mime: Option<&str>) -> Result<(Vec<u8>, Option<String>)> {
+    if data.len() <= MAX_IMAGE_SIZE {
+        return Ok((data, mime.map(str::to_string)));
+    }
+
+    let image = shrink_art(art_reader(&data, mime)?.decode()?).into_rgb8();
+    let mut encoded = Cursor::new(vec![]);
+    image.write_to(&mut encoded, ImageFormat::Jpeg)?;
+    Ok((encoded.into_inner(), Some("image/jpeg".to_string())))
+}
+
+/// Client for an AT Protocol PDS, logged in with an app password.
+pub struct Bluesky {
+    client: Client,
+    pds: String,
+    identifier: String,
+    password: String,
+    session: Mutex<Option<Session>>,
+    /// The profile record as last read or written, so updates don't have to fetch it again.
+    record: Mutex<Option<ProfileRecord>>,
+}
+
+impl Bluesky {
+    pub fn new(pds: &str, identifier: &str, password: &str) -> Self {
+        Self {
+            client: Client::new(),
+            pds: pds.to_string(),
+            identifier: identifier.to_string(),
+            password: password.to_string(),
+            session: Mutex::new(None),
+            record: Mutex::new(None),
+        }
+    }
+
+    fn xrpc(&self, method: &str) -> String {
+        format!("{}/xrpc/{}", self.pds, method)
+    }
+
+    async fn session(&self) -> Result<Session> {
+        let mut session = self.session.lock().await;
+
+        if let Some(session) = &*session {
+            return Ok(session.clone());
+        }
+
+        let body = CreateSession {
+            identifier: &self.identifier,
+            password: &self.password,
+        };
+        let new_session: Session = self
+            .client
+            .post(self.xrpc("com.atproto.server.createSession"))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
+
+    /// Makes an authenticated call, logging in again once if the access token has expired.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        request: impl Fn(&Session) -> RequestBuilder,
+    ) -> Result<T> {
+        let mut retried = false;
+
+        loop {
+            let session = self.session().await?;
+            let response = request(&session)
+                .bearer_auth(&session.access_jwt)
+                .send()
+                .await?;
+
+            if response.status().is_success() {
+                break Ok(response.json().await?);
+            }
+
+            let status = response.status();
+            let error: XrpcError = match response.json().await {
+                Ok(error) => error,
+                Err(_) => bail!("PDS responded with {}", status),
+            };
+
+            if status == StatusCode::BAD_REQUEST && error.error == "ExpiredToken" && !retried {
+                *self.session.lock().await = None;
+                retried = true;
+                continue;
+            }
+
+            bail!(
+                "{}: {}",
+                error.error,
+                error.message.as_deref().unwrap_or("no message")
+            );
+        }
+    }
+
+    /// The profile record, which doesn't exist for accounts that never edited their profile.
+    async fn profile_record(&self, session: &Session) -> Result<ProfileRecord> {
+        let response = self
+            .client
+            .get(self.xrpc("com.atproto.repo.getRecord"))
+            .query(&[
+                ("repo", session.did.as_str()),
+                ("collection", PROFILE_COLLECTION),
+                ("rkey", "self"),
+            ])
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::BAD_REQUEST {
+            let error: XrpcError = response.json().await?;
+            if error.error == "RecordNotFound" {
+                return Ok(ProfileRecord {
+                    cid: None,
+                    value: json!({ "$type": PROFILE_COLLECTION }),
+                });
+            }
+            bail!("{}", error.error);
+        }
+
+        Ok(response.error_for_status()?.json().await?)
+    }
+}
+
+fn to_profile(session: &Session, record: &ProfileRecord) -> Profile {
+    Profile {
+        acct: session.handle.clone(),
+        note: record.value["description"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        fields: vec![],
+    }
+}
+
+#[async_trait::async_trait]
+impl ProfileBackend for Bluesky {
+    async fn profile(&self) -> Result<Profile> {
+        let session = self.session().await?;
+        let record = self.profile_record(&session).await?;
+        let profile = to_profile(&session, &record);
+        *self.record.lock().await = Some(record);
+        Ok(profile)
+    }
+
+    async fn set_note(&self, note: &str) -> Result<Profile> {
+        let session = self.session().await?;
+        // Taken out so a failed write, like a lost swap, makes the next one fetch it again.
+        let cached = self.record.lock().await.take();
+        let mut record = match cached {
+            Some(record) => record,
+            None => self.profile_record(&session).await?,
+        };
+
+        // The rest of the record, like the avatar, has to be written back unchanged.
+        record.value["description"] = Value::from(note);
+
+        let written: WrittenRecord = self
+            .call(|session| {
+                let mut body = json!({
+                    "repo": session.did,
+                    "collection": PROFILE_COLLECTION,
+                    "rkey": "self",
+                    "record": record.value,
+                });
+                if let Some(cid) = &record.cid {
+                    body["swapRecord"] = Value::from(cid.as_str());
+                }
+
+                self.client
+                    .post(self.xrpc("com.atproto.repo.putRecord"))
+                    .json(&body)
+            })
+            .await?;
+
+        record.cid = Some(written.cid);
+        let profile = to_profile(&session, &record);
+        *self.record.lock().await = Some(record);
+        Ok(profile)
+    }
+
+    async fn set_fields(&self, _fields: &[Field]) -> Result<Profile> {
+        bail!("Bluesky profiles don't have fields");
+    }
+
+    /// Returns the blob reference itself, serialized, as there's no separate ID.
+    async fn upload_media(&self, data: Vec<u8>, mime: Option<&str>) -> Result<String> {
+        let (data, mime) = fit_image(data, mime)?;
+        let mime = mime.unwrap_or_else(|| "application/octet-stream".to_string());
+
+        let uploaded: UploadedBlob = self
+            .call(|_| {
+                self.client
+                    .post(self.xrpc("com.atproto.repo.uploadBlob"))
+                    .header(CONTENT_TYPE, &mime)
+                    .body(data.clone())
+            })
+            .await?;
+        Ok(uploaded.blob.to_string())
+    }
+
+    async fn post(&self, text: &str, _visibility: Visibility, media_ids: &[String]) -> Result<()> {
+        let text = truncate(text, MAX_POST);
+
+        let mut record = json!({
+            "$type": POST_COLLECTION,
+            "text": text,
+            "createdAt": humantime::format_rfc3339(SystemTime::now()).to_string(),
+        });
+
+        let facets = hashtag_facets(&text);
+        if !facets.is_empty() {
+            record["facets"] = Value::from(facets);
+        }
+
+        if !media_ids.is_empty() {
+            let images = media_ids
+                .iter()
+                .map(|blob| {
+                    let blob: Value = serde_json::from_str(blob)?;
+                    Ok(json!({ "alt": "Album cover", "image": blob }))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            record["embed"] = json!({ "$type": "app.bsky.embed.images", "images": images });
+        }
+
+        let _: Value = self
+            .call(|session| {
+                self.client
+                    .post(self.xrpc("com.atproto.repo.createRecord"))
+                    .json(&json!({
+                        "repo": session.did,
+                        "collection": POST_COLLECTION,
+                        "record": record,
+                    }))
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn max_note(&self) -> usize {
+        MAX_DESCRIPTION
+    }
+
+    fn max_fields(&self) -> usize {
+        0
+    }
+
+    fn max_field_value(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_util::mock_server;
+    use axum::http::HeaderMap;
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    async fn server(record_reads: Arc<AtomicUsize>) -> String {
+        let app = Router::new()
+            .route(
+                "/xrpc/com.atproto.server.createSession",
+                post(|| async {
+                    Json(json!({ "accessJwt": "jwt", "did": "did:plc:user", "handle": "user" }))
+                }),
+            )
+            .route(
+                "/xrpc/com.atproto.repo.getRecord",
+                get(move || async move {
+                    record_reads.fetch_add(1, Ordering::SeqCst);
+                    Json(json!({
+                        "cid": "cid0",
+                        "value": { "$type": PROFILE_COLLECTION, "description": "Hello" },
+                    }))
+                }),
+            )
+            .route(
+                "/xrpc/com.atproto.repo.putRecord",
+                post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                    assert_eq!(headers["authorization"], "Bearer jwt");
+                    let swap = body["swapRecord"].as_str().unwrap();
+                    let cid = format!("cid{}", swap[3..].parse::<u32>().unwrap() + 1);
+                    Json(json!({ "uri": "at://did:plc:user/profile/self", "cid": cid }))
+                }),
+            );
+        mock_server(app).await
+    }
+
+    #[tokio::test]
+    async fn set_note_reuses_record() {
+        let record_reads = Arc::new(AtomicUsize::new(0));
+        let bluesky = Bluesky::new(&server(record_reads.clone()).await, "user", "password");
+
+        assert_eq!(bluesky.profile().await.unwrap().note, "Hello");
+        for note in ["First", "Second"].iter() {
+            let profile = ProfileBackend::set_note(&bluesky, note).await.unwrap();
+            assert_eq!(profile.note, *note);
+        }
+
+        assert_eq!(record_reads.load(Ordering::SeqCst), 1);
+        let record = bluesky.record.lock().await.clone().unwrap();
+        assert_eq!(record.cid.as_deref(), Some("cid2"));
+    }
+
+    #[test]
+    fn hashtags() {
+        let facets = hashtag_facets("Now listening to: é #nowplaying #");
+        assert_eq!(facets.len(), 1);
+        assert_eq!(facets[0]["index"]["byteStart"], 21);
+        assert_eq!(facets[0]["features"][0]["tag"], "nowplaying");
+    }
+}
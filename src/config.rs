@@ -1,10 +1,14 @@
-use anyhow::Result;
+use super::mpd::{Change, IdleSubsystem};
+use anyhow::{bail, Result};
+use log::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
+use toml::{Table, Value};
 
 #[derive(Serialize, Deserialize)]
 pub struct WebConfig {
@@ -12,21 +16,904 @@ pub struct WebConfig {
     pub public_addr: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    /// How long the status has to stay unchanged before it is sent.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Minimum time between two updates sent to the same sink.
+    #[serde(default = "default_min_interval_ms")]
+    pub min_interval_ms: u64,
+}
+
+fn default_debounce_ms() -> u64 {
+    1000
+}
+
+fn default_min_interval_ms() -> u64 {
+    5000
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            debounce_ms: default_debounce_ms(),
+            min_interval_ms: default_min_interval_ms(),
+        }
+    }
+}
+
+impl RateLimit {
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_millis(self.min_interval_ms)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct RateLimits {
+    #[serde(default)]
+    pub discord: RateLimit,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MastodonMode {
+    Bio,
+    Field,
+    Post,
+}
+
+/// Which API the account's server speaks.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Mastodon,
+    Pleroma,
+    Akkoma,
+    GoToSocial,
+    Misskey,
+    Sharkey,
+    Bluesky,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    Unlisted,
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Unlisted => "unlisted",
+            Self::Private => "private",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MastodonPostConfig {
+    #[serde(default = "default_visibility")]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub attach_cover: bool,
+    #[serde(default)]
+    pub hashtags: Vec<String>,
+    /// How long a track has to be played before it gets posted.
+    #[serde(default = "default_min_listen_secs")]
+    pub min_listen_secs: u64,
+    /// Tracks that would be posted sooner than this after the previous post are skipped.
+    #[serde(default = "default_min_interval_minutes")]
+    pub min_interval_minutes: u64,
+}
+
+fn default_visibility() -> Visibility {
+    Visibility::Unlisted
+}
+
+fn default_min_listen_secs() -> u64 {
+    30
+}
+
+fn default_min_interval_minutes() -> u64 {
+    5
+}
+
+impl Default for MastodonPostConfig {
+    fn default() -> Self {
+        Self {
+            visibility: default_visibility(),
+            attach_cover: false,
+            hashtags: vec![],
+            min_listen_secs: default_min_listen_secs(),
+            min_interval_minutes: default_min_interval_minutes(),
+        }
+    }
+}
+
+impl MastodonPostConfig {
+    pub fn min_listen(&self) -> Duration {
+        Duration::from_secs(self.min_listen_secs)
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_secs(self.min_interval_minutes * 60)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MastodonConfig {
+    /// Base URL of the server, or of the PDS for Bluesky.
+    #[serde(default = "default_instance")]
+    pub instance: String,
+    /// Access token, or an app password for Bluesky.
+    pub token: String,
+    /// Handle to log in with, only needed for Bluesky.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default = "default_backend")]
+    pub backend: Backend,
+    #[serde(default = "default_mastodon_modes")]
+    pub modes: Vec<MastodonMode>,
+    /// Name of the profile metadata field used in field mode.
+    #[serde(default = "default_field_name")]
+    pub field_name: String,
+    /// How often the profile is re-fetched to pick up edits made elsewhere.
+    #[serde(default = "default_refresh_minutes")]
+    pub refresh_minutes: u64,
+    /// Template for the text used in every mode, defaults to the same text as the bio.
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub post: MastodonPostConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+impl MastodonConfig {
+    pub fn refresh(&self) -> Duration {
+        Duration::from_secs(self.refresh_minutes * 60)
+    }
+}
+
+fn default_instance() -> String {
+    "https://60228.dev".into()
+}
+
+fn default_backend() -> Backend {
+    Backend::Mastodon
+}
+
+fn default_field_name() -> String {
+    "Now playing".into()
+}
+
+fn default_refresh_minutes() -> u64 {
+    30
+}
+
+fn default_mastodon_modes() -> Vec<MastodonMode> {
+    vec![MastodonMode::Bio]
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LastFmConfig {
+    pub api_key: String,
+    pub api_secret: String,
+    /// Obtained by running `mpdiscord <config> lastfm-auth`.
+    #[serde(default)]
+    pub session_key: Option<String>,
+    #[serde(default = "default_lastfm_api_base")]
+    pub api_base: String,
+    /// Where scrobbles that couldn't be submitted yet are kept, relative to the configuration
+    /// file.
+    #[serde(default = "default_lastfm_queue_path")]
+    pub queue_path: PathBuf,
+}
+
+fn default_lastfm_api_base() -> String {
+    "https://ws.audioscrobbler.com/2.0/".into()
+}
+
+fn default_lastfm_queue_path() -> PathBuf {
+    "lastfm-queue.json".into()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListenBrainzConfig {
+    pub token: String,
+    #[serde(default = "default_listenbrainz_api_base")]
+    pub api_base: String,
+    /// Where listens that couldn't be submitted yet are kept, relative to the configuration file.
+    #[serde(default = "default_listenbrainz_queue_path")]
+    pub queue_path: PathBuf,
+}
+
+fn default_listenbrainz_api_base() -> String {
+    "https://api.listenbrainz.org".into()
+}
+
+fn default_listenbrainz_queue_path() -> PathBuf {
+    "listenbrainz-queue.json".into()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryConfig {
+    /// Relative to the configuration file.
+    #[serde(default = "default_history_path")]
+    pub path: PathBuf,
+}
+
+fn default_history_path() -> PathBuf {
+    "history.sqlite".into()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// JSON body with `{name}` placeholders, which are escaped to be used inside strings. The
+    /// braces of JSON objects have to be doubled.
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Key used to sign the body with HMAC-SHA256.
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_changes")]
+    pub changes: Vec<Change>,
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// After this many deliveries failed in a row, the hook is disabled for a while.
+    #[serde(default = "default_webhook_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_webhook_disable_minutes")]
+    pub disable_minutes: u64,
+}
+
+fn default_webhook_changes() -> Vec<Change> {
+    Change::ALL.to_vec()
+}
+
+fn default_webhook_timeout_secs() -> u64 {
+    10
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+fn default_webhook_max_failures() -> u32 {
+    5
+}
+
+fn default_webhook_disable_minutes() -> u64 {
+    10
+}
+
+impl WebhookConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn disable_for(&self) -> Duration {
+        Duration::from_secs(self.disable_minutes * 60)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_base_topic")]
+    pub base_topic: String,
+    /// Whether to announce the sensors through Home Assistant's MQTT discovery.
+    #[serde(default)]
+    pub discovery: bool,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "mpdiscord".into()
+}
+
+fn default_mqtt_base_topic() -> String {
+    "mpdiscord".into()
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".into()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FileConfig {
+    pub path: PathBuf,
+    /// Defaults to the same text as the Mastodon bio.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Written when playback stops, an empty string clears the file. If unset, the file keeps
+    /// showing the last song.
+    #[serde(default)]
+    pub stopped_text: Option<String>,
+    /// Where to write the current song's cover, which is removed when there isn't one.
+    #[serde(default)]
+    pub art_path: Option<PathBuf>,
+}
+
+/// Shell commands run on changes, with the template variables in `MPDISCORD_*` environment
+/// variables.
+#[derive(Serialize, Deserialize)]
+pub struct ExecConfig {
+    #[serde(default)]
+    pub track_changed: Option<String>,
+    #[serde(default)]
+    pub paused: Option<String>,
+    #[serde(default)]
+    pub resumed: Option<String>,
+    #[serde(default)]
+    pub stopped: Option<String>,
+    #[serde(default = "default_exec_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Where to save the cover, passed to commands as `MPDISCORD_ART`.
+    #[serde(default)]
+    pub art_path: Option<PathBuf>,
+}
+
+fn default_exec_timeout_secs() -> u64 {
+    30
+}
+
+impl ExecConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NotificationsConfig {
+    #[serde(default)]
+    pub notify_pause: bool,
+    #[serde(default)]
+    pub notify_stop: bool,
+    /// How long notifications stay up, -1 leaves it to the notification server.
+    #[serde(default = "default_notification_timeout_ms")]
+    pub timeout_ms: i32,
+    /// Uses this bus instead of the session bus.
+    #[serde(default)]
+    pub bus_address: Option<String>,
+}
+
+fn default_notification_timeout_ms() -> i32 {
+    -1
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MprisConfig {
+    /// Where to cache covers for `mpris:artUrl` when the art server isn't configured.
+    #[serde(default)]
+    pub art_dir: Option<PathBuf>,
+    /// Uses this bus instead of the session bus.
+    #[serde(default)]
+    pub bus_address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamServer {
+    Icecast,
+    Shoutcast,
+}
+
+/// A stream server whose metadata is updated on track changes.
+#[derive(Serialize, Deserialize)]
+pub struct IcecastConfig {
+    /// Base URL of the server, such as `http://localhost:8000`.
+    pub url: String,
+    #[serde(default = "default_stream_server")]
+    pub server: StreamServer,
+    /// Mount point to update, required for Icecast.
+    #[serde(default)]
+    pub mount: Option<String>,
+    /// Stream ID, for Shoutcast v2 servers with more than one stream.
+    #[serde(default)]
+    pub sid: Option<u32>,
+    #[serde(default = "default_icecast_username")]
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default = "default_icecast_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_icecast_retries")]
+    pub retries: u32,
+}
+
+fn default_stream_server() -> StreamServer {
+    StreamServer::Icecast
+}
+
+fn default_icecast_username() -> String {
+    "admin".into()
+}
+
+fn default_icecast_timeout_secs() -> u64 {
+    10
+}
+
+fn default_icecast_retries() -> u32 {
+    3
+}
+
+impl IcecastConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// An IRC connection, which also works for Twitch chat through `irc.chat.twitch.tv`.
+#[derive(Serialize, Deserialize)]
+pub struct IrcConfig {
+    pub server: String,
+    /// Defaults to 6697 with TLS and 6667 without.
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default = "default_irc_tls")]
+    pub tls: bool,
+    pub nickname: String,
+    /// Server password, which is `oauth:` followed by a token for Twitch.
+    #[serde(default)]
+    pub password: Option<String>,
+    pub channels: Vec<String>,
+    #[serde(default = "default_irc_command")]
+    pub command: String,
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Whether to say every track change in all channels.
+    #[serde(default)]
+    pub announce: bool,
+    /// How long to ignore the command in a channel after answering it there.
+    #[serde(default = "default_irc_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+fn default_irc_tls() -> bool {
+    true
+}
+
+fn default_irc_command() -> String {
+    "!song".into()
+}
+
+fn default_irc_cooldown_secs() -> u64 {
+    30
+}
+
+impl IrcConfig {
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown_secs)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MatrixConfig {
+    pub homeserver: String,
+    pub access_token: String,
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Room to post track changes to, by ID.
+    #[serde(default)]
+    pub room_id: Option<String>,
+    #[serde(default)]
+    pub attach_cover: bool,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SlackConfig {
+    /// User token with the `users.profile:write` scope.
+    pub token: String,
+    #[serde(default = "default_slack_emoji")]
+    pub emoji: String,
+    #[serde(default)]
+    pub template: Option<String>,
+    /// How long after the end of the track the status expires, so it doesn't stay up if we die.
+    #[serde(default = "default_slack_expire_minutes")]
+    pub expire_minutes: u64,
+    #[serde(default = "default_slack_api_base")]
+    pub api_base: String,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+fn default_slack_emoji() -> String {
+    ":musical_note:".into()
+}
+
+fn default_slack_expire_minutes() -> u64 {
+    10
+}
+
+fn default_slack_api_base() -> String {
+    "https://slack.com/api".into()
+}
+
+impl SlackConfig {
+    pub fn expire_after(&self) -> Duration {
+        Duration::from_secs(self.expire_minutes * 60)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GitHubConfig {
+    /// Token with the `user` scope.
+    pub token: String,
+    #[serde(default = "default_github_emoji")]
+    pub emoji: String,
+    #[serde(default)]
+    pub template: Option<String>,
+    /// If set, the status expires this long after the end of the track.
+    #[serde(default)]
+    pub expire_minutes: Option<u64>,
+    #[serde(default = "default_github_api_url")]
+    pub api_url: String,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+fn default_github_emoji() -> String {
+    ":musical_note:".into()
+}
+
+fn default_github_api_url() -> String {
+    "https://api.github.com/graphql".into()
+}
+
+impl GitHubConfig {
+    pub fn expire_after(&self) -> Option<Duration> {
+        self.expire_minutes.map(|x| Duration::from_secs(x * 60))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct XmppConfig {
+    pub jid: String,
+    pub password: String,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+/// Makes mpdiscord its own fediverse actor, served by the web server at its `public_addr`.
+#[derive(Serialize, Deserialize)]
+pub struct ActivityPubConfig {
+    #[serde(default = "default_activitypub_username")]
+    pub username: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Where the actor's private key is kept, which is generated if it doesn't exist.
+    #[serde(default = "default_activitypub_key_path")]
+    pub key_path: PathBuf,
+    #[serde(default = "default_activitypub_followers_path")]
+    pub followers_path: PathBuf,
+    #[serde(default = "default_activitypub_outbox_path")]
+    pub outbox_path: PathBuf,
+    /// Actors that were already sent a follow request, so they aren't sent another.
+    #[serde(default = "default_activitypub_following_path")]
+    pub following_path: PathBuf,
+    /// Deliveries that failed and are waiting to be retried.
+    #[serde(default = "default_activitypub_deliveries_path")]
+    pub deliveries_path: PathBuf,
+    /// How many of the latest activities are kept in the outbox.
+    #[serde(default = "default_activitypub_outbox_size")]
+    pub outbox_size: usize,
+    /// IDs of actors to send follow requests to on startup.
+    #[serde(default)]
+    pub follow: Vec<String>,
+}
+
+fn default_activitypub_username() -> String {
+    "nowplaying".into()
+}
+
+fn default_activitypub_key_path() -> PathBuf {
+    "activitypub-key.pem".into()
+}
+
+fn default_activitypub_followers_path() -> PathBuf {
+    "activitypub-followers.json".into()
+}
+
+fn default_activitypub_outbox_path() -> PathBuf {
+    "activitypub-outbox.json".into()
+}
+
+fn default_activitypub_following_path() -> PathBuf {
+    "activitypub-following.json".into()
+}
+
+fn default_activitypub_deliveries_path() -> PathBuf {
+    "activitypub-deliveries.json".into()
+}
+
+fn default_activitypub_outbox_size() -> usize {
+    50
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub artfiles: Vec<String>,
     pub discord_client_id: i64,
-    pub mastodon_token: String,
+
+    #[serde(default)]
+    pub activitypub: Option<ActivityPubConfig>,
+
+    #[serde(default)]
+    pub mastodon: Vec<MastodonConfig>,
+
+    #[serde(default)]
+    pub exec: Option<ExecConfig>,
+
+    #[serde(default)]
+    pub file: Vec<FileConfig>,
+
+    #[serde(default)]
+    pub github: Option<GitHubConfig>,
+
+    #[serde(default)]
+    pub history: Option<HistoryConfig>,
+
+    #[serde(default)]
+    pub icecast: Vec<IcecastConfig>,
+
+    #[serde(default)]
+    pub irc: Option<IrcConfig>,
+
+    #[serde(default)]
+    pub lastfm: Option<LastFmConfig>,
+
+    #[serde(default)]
+    pub listenbrainz: Option<ListenBrainzConfig>,
+
+    #[serde(default)]
+    pub matrix: Option<MatrixConfig>,
+
+    #[serde(default)]
+    pub mpris: Option<MprisConfig>,
+
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+
+    #[serde(default)]
+    pub notifications: Option<NotificationsConfig>,
+
+    #[serde(default)]
+    pub slack: Option<SlackConfig>,
+
+    #[serde(default)]
+    pub webhook: Vec<WebhookConfig>,
 
     #[serde(default)]
     pub web: Option<WebConfig>,
 
+    #[serde(default)]
+    pub xmpp: Option<XmppConfig>,
+
     #[serde(default)]
     pub art_overrides: HashMap<String, String>,
+
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+
+    #[serde(default = "default_idle_subsystems")]
+    pub idle_subsystems: Vec<IdleSubsystem>,
+}
+
+fn default_idle_subsystems() -> Vec<IdleSubsystem> {
+    vec![
+        IdleSubsystem::Player,
+        IdleSubsystem::Queue,
+        IdleSubsystem::Options,
+    ]
+}
+
+/// Moves the settings of the old single-account layout, with `mastodon_token` at the top level,
+/// into a `[[mastodon]]` entry.
+fn migrate_mastodon(config: &mut Table) -> Result<()> {
+    let rate_limit = match config.get_mut("rate_limits") {
+        Some(Value::Table(rate_limits)) => rate_limits.remove("mastodon"),
+        _ => None,
+    };
+
+    let token = match config.remove("mastodon_token") {
+        Some(token) => token,
+        None if rate_limit.is_some() => {
+            bail!("rate_limits.mastodon has moved to the rate_limit of each [[mastodon]] account")
+        }
+        None => return Ok(()),
+    };
+
+    warn!("mastodon_token is deprecated, move it into a [[mastodon]] account");
+
+    let mut account = match config.remove("mastodon") {
+        Some(Value::Table(account)) => account,
+        Some(_) => bail!("mastodon_token can't be combined with [[mastodon]] accounts"),
+        None => Table::new(),
+    };
+    account.insert("token".into(), token);
+    if let Some(rate_limit) = rate_limit {
+        account.insert("rate_limit".into(), rate_limit);
+    }
+
+    config.insert("mastodon".into(), Value::Array(vec![Value::Table(account)]));
+    Ok(())
+}
+
+impl Config {
+    /// Catches settings that only make sense together, which serde can't check on its own.
+    fn validate(&self) -> Result<()> {
+        for account in &self.mastodon {
+            if account.backend != Backend::Bluesky {
+                continue;
+            }
+
+            if account.username.is_none() {
+                bail!("Bluesky accounts need a username to log in with");
+            }
+            if account.modes.contains(&MastodonMode::Field) {
+                bail!("Bluesky profiles don't have fields, so field mode can't be used");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the files mpdiscord keeps its state in relative to the configuration file, rather
+    /// than to wherever it was started from.
+    fn resolve_paths(&mut self, base: &Path) {
+        if let Some(lastfm) = &mut self.lastfm {
+            lastfm.queue_path = base.join(&lastfm.queue_path);
+        }
+
+        if let Some(listenbrainz) = &mut self.listenbrainz {
+            listenbrainz.queue_path = base.join(&listenbrainz.queue_path);
+        }
+
+        if let Some(history) = &mut self.history {
+            history.path = base.join(&history.path);
+        }
+
+        if let Some(activitypub) = &mut self.activitypub {
+            activitypub.key_path = base.join(&activitypub.key_path);
+            activitypub.followers_path = base.join(&activitypub.followers_path);
+            activitypub.outbox_path = base.join(&activitypub.outbox_path);
+            activitypub.following_path = base.join(&activitypub.following_path);
+            activitypub.deliveries_path = base.join(&activitypub.deliveries_path);
+        }
+    }
+}
+
+fn parse_config(config_text: &str) -> Result<Config> {
+    let mut table: Table = toml::from_str(config_text)?;
+    migrate_mastodon(&mut table)?;
+
+    let config: Config = table.try_into()?;
+    config.validate()?;
+    Ok(config)
 }
 
 pub async fn read_config(path: impl AsRef<Path>) -> Result<Arc<Config>> {
+    let path = path.as_ref();
     let config_text = fs::read_to_string(path).await?;
-    let parsed = toml::from_str(&config_text)?;
-    Ok(Arc::new(parsed))
+
+    let mut config = parse_config(&config_text)?;
+    config.resolve_paths(path.parent().unwrap_or_else(|| Path::new("")));
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+
+    /// A configuration with no sinks set up, plus whatever `extra` adds.
+    pub fn config(extra: &str) -> Config {
+        parse_config(&format!("artfiles = []\ndiscord_client_id = 0\n{}", extra)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_single_account() {
+        let config = parse_config(
+            r#"
+            artfiles = []
+            discord_client_id = 0
+            mastodon_token = "token"
+
+            [mastodon]
+            instance = "https://example.com"
+
+            [rate_limits.mastodon]
+            min_interval_ms = 1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.mastodon.len(), 1);
+        assert_eq!(config.mastodon[0].instance, "https://example.com");
+        assert_eq!(config.mastodon[0].token, "token");
+        assert_eq!(config.mastodon[0].rate_limit.min_interval_ms, 1);
+    }
+
+    #[test]
+    fn rejects_stray_mastodon_rate_limit() {
+        let err = parse_config(
+            r#"
+            artfiles = []
+            discord_client_id = 0
+
+            [[mastodon]]
+            token = "token"
+
+            [rate_limits.mastodon]
+            min_interval_ms = 1
+            "#,
+        );
+
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn bluesky_needs_username() {
+        let account = "[[mastodon]]\ninstance = \"https://bsky.social\"\ntoken = \"password\"\nbackend = \"bluesky\"\n";
+        let base = "artfiles = []\ndiscord_client_id = 0\n";
+
+        assert!(parse_config(&format!("{}{}", base, account)).is_err());
+        parse_config(&format!(
+            "{}{}username = \"user.bsky.social\"\n",
+            base, account
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn bluesky_has_no_field_mode() {
+        let config = r#"
+            artfiles = []
+            discord_client_id = 0
+            [[mastodon]]
+            instance = "https://bsky.social"
+            token = "password"
+            backend = "bluesky"
+            username = "user.bsky.social"
+            modes = ["bio", "field"]
+        "#;
+        assert!(parse_config(config).is_err());
+    }
 }
@@ -1,10 +1,13 @@
 use super::config::Config;
 use super::mpd::SongStatus;
+use super::template::{self, Vars};
 use anyhow::Result;
 use discord_sdk::activity::{Activity, ActivityKind, Assets, Timestamps};
 use log::*;
 use mpd_client::responses::{PlayState, Song};
+use mpd_client::tag::Tag;
 use rand::distr::{Alphanumeric, SampleString};
+use std::borrow::Cow;
 use std::fmt::Write;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -31,7 +34,7 @@ fn slugify(title: &str, config: &Config) -> String {
         .collect()
 }
 
-fn get_artist(song: &Song) -> Option<String> {
+pub fn get_artist(song: &Song) -> Option<String> {
     let artists = song.artists();
     let list = if !artists.is_empty() {
         artists
@@ -46,9 +49,29 @@ fn get_artist(song: &Song) -> Option<String> {
     }
 }
 
-pub fn get_activity(song_status: &SongStatus, config: &Config) -> Result<Activity> {
-    let time = SystemTime::now();
+pub fn get_timestamps(song_status: &SongStatus) -> Result<Option<Timestamps>> {
+    if song_status.status.state != PlayState::Playing {
+        return Ok(None);
+    }
+
+    if let Some(position) = song_status.position {
+        debug!("Elapsed: {:?}", position.elapsed);
+
+        let since_epoch = position.started_at().duration_since(UNIX_EPOCH)?;
+        let end = song_status
+            .status
+            .duration
+            .map(|duration| (duration.as_secs() + since_epoch.as_secs()) as _);
+        Ok(Some(Timestamps {
+            start: Some(since_epoch.as_secs() as _),
+            end,
+        }))
+    } else {
+        Ok(None)
+    }
+}
 
+pub fn get_activity(song_status: &SongStatus, config: &Config) -> Result<Activity> {
     trace!("creating Activity");
     let mut activity = Activity::default();
 
@@ -99,22 +122,7 @@ pub fn get_activity(song_status: &SongStatus, config: &Config) -> Result<Activit
         activity.state = Some(state);
     }
 
-    if song_status.status.state == PlayState::Playing {
-        if let Some(elapsed) = song_status.status.elapsed {
-            debug!("Elapsed: {:?}", elapsed);
-
-            let start = time - elapsed;
-            let since_epoch = start.duration_since(UNIX_EPOCH)?;
-            let end = song_status
-                .status
-                .duration
-                .map(|duration| (duration.as_secs() + since_epoch.as_secs()) as _);
-            activity.timestamps = Some(Timestamps {
-                start: Some(since_epoch.as_secs() as _),
-                end,
-            });
-        }
-    }
+    activity.timestamps = get_timestamps(song_status)?;
 
     activity.kind = ActivityKind::Listening;
 
@@ -133,3 +141,183 @@ pub fn get_text(song_status: &SongStatus) -> Option<String> {
 
     Some(notice)
 }
+
+/// The text shown for the current song: the template rendered if there is one, and the default
+/// text otherwise. `None` when there is no song.
+pub fn render_text(
+    template: Option<&str>,
+    song_status: &SongStatus,
+    config: &Config,
+) -> Option<String> {
+    song_status.song.as_ref()?;
+
+    match template {
+        Some(template) => Some(template::render(template, &get_vars(song_status, config))),
+        None => get_text(song_status),
+    }
+}
+
+/// Text for a status with a length limit, shortened with an ellipsis if needed. `None` when
+/// stopped, since the status should be cleared then.
+pub fn status_text(
+    template: Option<&str>,
+    song_status: &SongStatus,
+    config: &Config,
+    max: usize,
+) -> Option<String> {
+    if song_status.status.state == PlayState::Stopped {
+        return None;
+    }
+
+    let text = render_text(template, song_status, config)?;
+    if text.chars().count() <= max {
+        return Some(text);
+    }
+
+    let mut truncated: String = text.chars().take(max - 1).collect();
+    truncated.push('…');
+    Some(truncated)
+}
+
+/// When the current track will end, or now if it isn't playing. Statuses that clear themselves
+/// expire some time after this, in case we aren't around to clear them.
+pub fn track_end(song_status: &SongStatus) -> SystemTime {
+    let status = &song_status.status;
+    match (status.state, song_status.position, status.duration) {
+        (PlayState::Playing, Some(position), Some(duration)) => position.started_at() + duration,
+        _ => SystemTime::now(),
+    }
+}
+
+pub fn state_name(state: PlayState) -> &'static str {
+    match state {
+        PlayState::Playing => "playing",
+        PlayState::Paused => "paused",
+        PlayState::Stopped => "stopped",
+    }
+}
+
+pub fn get_art_url(song_status: &SongStatus, config: &Config) -> Option<String> {
+    let web_config = config.web.as_ref()?;
+    let song_id = song_status.song_id()?;
+    Some(format!("{}/art/{}", web_config.public_addr, song_id.0))
+}
+
+/// The name MPD uses for a tag, as `mpd_client` doesn't expose it.
+pub fn tag_name(tag: &Tag) -> Cow<'static, str> {
+    Cow::Borrowed(match tag {
+        Tag::Other(raw) => return Cow::Owned(raw.to_string()),
+        Tag::Album => "Album",
+        Tag::AlbumArtist => "AlbumArtist",
+        Tag::AlbumArtistSort => "AlbumArtistSort",
+        Tag::AlbumSort => "AlbumSort",
+        Tag::Artist => "Artist",
+        Tag::ArtistSort => "ArtistSort",
+        Tag::Comment => "Comment",
+        Tag::Composer => "Composer",
+        Tag::ComposerSort => "ComposerSort",
+        Tag::Conductor => "Conductor",
+        Tag::Date => "Date",
+        Tag::Disc => "Disc",
+        Tag::Ensemble => "Ensemble",
+        Tag::Genre => "Genre",
+        Tag::Grouping => "Grouping",
+        Tag::Label => "Label",
+        Tag::Location => "Location",
+        Tag::Movement => "Movement",
+        Tag::MovementNumber => "MovementNumber",
+        Tag::MusicBrainzArtistId => "MUSICBRAINZ_ARTISTID",
+        Tag::MusicBrainzRecordingId => "MUSICBRAINZ_TRACKID",
+        Tag::MusicBrainzReleaseArtistId => "MUSICBRAINZ_ALBUMARTISTID",
+        Tag::MusicBrainzReleaseId => "MUSICBRAINZ_ALBUMID",
+        Tag::MusicBrainzTrackId => "MUSICBRAINZ_RELEASETRACKID",
+        Tag::MusicBrainzWorkId => "MUSICBRAINZ_WORKID",
+        Tag::Name => "Name",
+        Tag::OriginalDate => "OriginalDate",
+        Tag::Performer => "Performer",
+        Tag::Title => "Title",
+        Tag::Track => "Track",
+        Tag::Work => "Work",
+        // Variants added to mpd_client after this was written.
+        _ => return Cow::Owned(format!("{:?}", tag)),
+    })
+}
+
+/// Variables available to templates. Every tag is available by its lowercase name, with
+/// multiple values joined by commas.
+pub fn get_vars(song_status: &SongStatus, config: &Config) -> Vars {
+    let mut vars = Vars::new();
+
+    vars.insert("state".into(), state_name(song_status.status.state).into());
+    vars.insert("change".into(), song_status.change.as_str().into());
+
+    if let Some(position) = song_status.position {
+        vars.insert("elapsed".into(), position.elapsed.as_secs().to_string());
+    }
+
+    if let Some(duration) = song_status.status.duration {
+        vars.insert("duration".into(), duration.as_secs().to_string());
+    }
+
+    if let Some(url) = get_art_url(song_status, config) {
+        vars.insert("art_url".into(), url);
+    }
+
+    if let Some(text) = get_text(song_status) {
+        vars.insert("text".into(), text);
+    }
+
+    if let Some(song) = &song_status.song {
+        for (tag, values) in &song.tags {
+            vars.insert(tag_name(tag).to_lowercase(), values.join(", "));
+        }
+
+        vars.insert("file".into(), song.url.clone());
+        vars.insert(
+            "artist".into(),
+            get_artist(song).unwrap_or_else(|| "Unknown Artist".to_string()),
+        );
+    }
+
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::mpd::test_util::playing;
+    use crate::mpd::Change;
+    use std::time::Duration;
+
+    #[test]
+    fn status_text_is_shortened() {
+        let config = test_util::config("");
+        let song_status =
+            playing(1, "A long title", 0.0, SystemTime::now()).with_change(Change::TrackChanged);
+
+        let text = status_text(None, &song_status, &config, 8);
+        assert_eq!(text.as_deref(), Some("A long …"));
+
+        let text = status_text(Some("{title}"), &song_status, &config, 12);
+        assert_eq!(text.as_deref(), Some("A long title"));
+
+        let mut stopped = song_status;
+        stopped.status.state = PlayState::Stopped;
+        assert_eq!(status_text(None, &stopped, &config, 100), None);
+    }
+
+    #[test]
+    fn track_end_follows_position() {
+        let measured_at = UNIX_EPOCH + Duration::from_secs(1_000);
+        let song_status = playing(1, "Title", 30.0, measured_at).with_change(Change::Seeked);
+        assert_eq!(
+            track_end(&song_status),
+            measured_at + Duration::from_secs(150)
+        );
+
+        let mut paused = song_status;
+        paused.status.state = PlayState::Paused;
+        assert!(track_end(&paused) > measured_at + Duration::from_secs(150));
+    }
+}
@@ -0,0 +1,195 @@
+use super::config::GitHubConfig;
+use anyhow::{bail, Result};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Longest status message GitHub accepts, in characters.
+pub const MAX_STATUS_MESSAGE: usize = 80;
+
+const CHANGE_USER_STATUS: &str = "mutation($input: ChangeUserStatusInput!) {
+  changeUserStatus(input: $input) { clientMutationId }
+}";
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ChangeUserStatusInput<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emoji: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Variables<'a> {
+    input: ChangeUserStatusInput<'a>,
+}
+
+#[derive(Serialize)]
+struct Query<'a> {
+    query: &'a str,
+    variables: Variables<'a>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct GraphQlResponse {
+    #[serde(default)]
+    errors: Vec<GraphQlError>,
+}
+
+pub struct GitHub {
+    client: Client,
+    api_url: String,
+    token: String,
+}
+
+impl GitHub {
+    pub fn new(config: &GitHubConfig) -> Self {
+        Self {
+            client: Client::new(),
+            api_url: config.api_url.clone(),
+            token: config.token.clone(),
+        }
+    }
+
+    async fn change_user_status(&self, input: ChangeUserStatusInput<'_>) -> Result<()> {
+        let query = Query {
+            query: CHANGE_USER_STATUS,
+            variables: Variables { input },
+        };
+
+        let response: GraphQlResponse = self
+            .client
+            .post(&self.api_url)
+            .bearer_auth(&self.token)
+            // GitHub rejects requests without a user agent.
+            .header("User-Agent", "mpdiscord")
+            .json(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // GraphQL errors come back with a 200.
+        if let Some(error) = response.errors.first() {
+            bail!("changeUserStatus failed: {}", error.message);
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_status(
+        &self,
+        message: &str,
+        emoji: &str,
+        expires_at: Option<SystemTime>,
+    ) -> Result<()> {
+        self.change_user_status(ChangeUserStatusInput {
+            emoji: Some(emoji),
+            message: Some(message),
+            expires_at: expires_at.map(|x| humantime::format_rfc3339_seconds(x).to_string()),
+        })
+        .await
+    }
+
+    /// Clears the status, since leaving out every field removes it.
+    pub async fn clear_status(&self) -> Result<()> {
+        self.change_user_status(ChangeUserStatusInput::default())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::util::test_util::mock_server;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    type Requests = Arc<Mutex<Vec<Value>>>;
+
+    /// A GraphQL endpoint that records every query and answers them all with `response`.
+    async fn github(response: Value) -> (GitHub, Requests) {
+        let requests = Requests::default();
+        let handler = {
+            let requests = requests.clone();
+            move |headers: HeaderMap, Json(body): Json<Value>| async move {
+                assert_eq!(headers["authorization"], "Bearer token");
+                assert_eq!(headers["user-agent"], "mpdiscord");
+                requests.lock().unwrap().push(body);
+                Json(response)
+            }
+        };
+        let app = Router::new().route("/graphql", post(handler));
+
+        let config = test_util::config(&format!(
+            "[github]\ntoken = \"token\"\napi_url = \"{}/graphql\"\n",
+            mock_server(app).await
+        ));
+        (GitHub::new(config.github.as_ref().unwrap()), requests)
+    }
+
+    fn success() -> Value {
+        json!({ "data": { "changeUserStatus": { "clientMutationId": null } } })
+    }
+
+    #[tokio::test]
+    async fn sets_status() {
+        let (github, requests) = github(success()).await;
+        let expires_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        github
+            .set_status("Title - Artist", ":notes:", Some(expires_at))
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0]["query"]
+            .as_str()
+            .unwrap()
+            .contains("changeUserStatus"));
+        assert_eq!(
+            requests[0]["variables"]["input"],
+            json!({
+                "emoji": ":notes:",
+                "message": "Title - Artist",
+                "expiresAt": "2023-11-14T22:13:20Z",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn clearing_sends_empty_input() {
+        let (github, requests) = github(success()).await;
+        github.clear_status().await.unwrap();
+
+        assert_eq!(requests.lock().unwrap()[0]["variables"]["input"], json!({}));
+    }
+
+    #[tokio::test]
+    async fn graphql_errors_fail() {
+        let response = json!({
+            "data": null,
+            "errors": [{ "message": "Your token has not been granted the required scopes" }],
+        });
+        let (github, _) = github(response).await;
+
+        let err = github
+            .set_status("Title", ":notes:", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("required scopes"), "{}", err);
+    }
+}
@@ -0,0 +1,408 @@
+use super::config::HistoryConfig;
+use super::conversions::tag_name;
+use super::listenbrainz::{AdditionalInfo, Listen, TrackMetadata};
+use super::mpd::Song;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS listens (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    listened_ms INTEGER NOT NULL,
+    duration_ms INTEGER,
+    file TEXT NOT NULL,
+    title TEXT,
+    artist TEXT,
+    album TEXT,
+    album_artist TEXT,
+    tags TEXT NOT NULL,
+    skipped INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS listens_started_at ON listens (started_at);
+";
+
+/// Listens are selected by `started_at` between these two Unix timestamps, either of which can
+/// be NULL for an open end.
+const RANGE: &str = "(?1 IS NULL OR started_at >= ?1) AND (?2 IS NULL OR started_at < ?2)";
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub started_at: u64,
+    pub listened_secs: u64,
+    pub duration_secs: Option<u64>,
+    pub file: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub skipped: bool,
+    pub tags: BTreeMap<String, Vec<String>>,
+}
+
+impl Entry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let tags: String = row.get("tags")?;
+        let listened_ms: u64 = row.get("listened_ms")?;
+        let duration_ms: Option<u64> = row.get("duration_ms")?;
+
+        Ok(Self {
+            started_at: row.get("started_at")?,
+            listened_secs: listened_ms / 1000,
+            duration_secs: duration_ms.map(|x| x / 1000),
+            file: row.get("file")?,
+            title: row.get("title")?,
+            artist: row.get("artist")?,
+            album: row.get("album")?,
+            album_artist: row.get("album_artist")?,
+            skipped: row.get("skipped")?,
+            tags: serde_json::from_str(&tags).unwrap_or_default(),
+        })
+    }
+
+    /// Converts the entry to a listen in the format ListenBrainz imports.
+    pub fn to_listen(&self) -> Option<Listen> {
+        let additional_info = AdditionalInfo::new(
+            |name| self.tags.get(name),
+            self.duration_secs.map(Duration::from_secs),
+        );
+
+        Some(Listen {
+            listened_at: Some(self.started_at),
+            track_metadata: TrackMetadata {
+                artist_name: self.artist.clone()?,
+                track_name: self.title.clone()?,
+                release_name: self.album.clone(),
+                additional_info,
+            },
+        })
+    }
+}
+
+/// How often something was listened to, not counting skips.
+pub struct Count {
+    pub name: String,
+    pub artist: Option<String>,
+    pub plays: u64,
+    pub listened_secs: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum TopKind {
+    Artists,
+    Albums,
+    Tracks,
+}
+
+pub struct History {
+    connection: Connection,
+}
+
+impl History {
+    pub fn open(config: &HistoryConfig) -> Result<Self> {
+        let connection = Connection::open(&config.path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    pub fn record(
+        &self,
+        song: &Song,
+        started_at: SystemTime,
+        listened: Duration,
+        skipped: bool,
+    ) -> Result<()> {
+        let tags: BTreeMap<_, _> = song
+            .tags
+            .iter()
+            .map(|(tag, values)| (tag_name(tag), values))
+            .collect();
+
+        self.connection.execute(
+            "INSERT INTO listens
+                (started_at, listened_ms, duration_ms, file, title, artist, album, album_artist,
+                 tags, skipped)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                started_at.duration_since(UNIX_EPOCH)?.as_secs(),
+                listened.as_millis() as u64,
+                song.duration.map(|x| x.as_millis() as u64),
+                song.url,
+                song.title(),
+                song.artists().first(),
+                song.album(),
+                song.album_artists().first(),
+                serde_json::to_string(&tags)?,
+                skipped,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn recent(&self, limit: u64) -> Result<Vec<Entry>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT * FROM listens ORDER BY started_at DESC LIMIT ?1")?;
+        let entries = statement
+            .query_map(params![limit], Entry::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Converts a date in any format SQLite understands to a Unix timestamp. SQLite would just
+    /// return NULL for anything else, which would quietly match nothing.
+    fn timestamp(&self, date: Option<&str>) -> Result<Option<i64>> {
+        let date = match date {
+            Some(date) => date,
+            None => return Ok(None),
+        };
+
+        let timestamp: Option<i64> = self.connection.query_row(
+            "SELECT CAST(strftime('%s', ?1) AS INTEGER)",
+            params![date],
+            |row| row.get(0),
+        )?;
+        timestamp
+            .map(Some)
+            .with_context(|| format!("invalid date {:?}", date))
+    }
+
+    pub fn range(&self, from: Option<&str>, to: Option<&str>) -> Result<Vec<Entry>> {
+        let (from, to) = (self.timestamp(from)?, self.timestamp(to)?);
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT * FROM listens WHERE {} ORDER BY started_at",
+            RANGE
+        ))?;
+        let entries = statement
+            .query_map(params![from, to], Entry::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    pub fn top(
+        &self,
+        kind: TopKind,
+        from: Option<&str>,
+        to: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<Count>> {
+        let (from, to) = (self.timestamp(from)?, self.timestamp(to)?);
+        let (name, artist) = match kind {
+            TopKind::Artists => ("artist", "NULL"),
+            TopKind::Albums => ("album", "COALESCE(album_artist, artist)"),
+            TopKind::Tracks => ("title", "artist"),
+        };
+
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {name} AS name, {artist} AS artist_name, COUNT(*) AS plays,
+                SUM(listened_ms) / 1000 AS listened_secs
+            FROM listens
+            WHERE NOT skipped AND {name} IS NOT NULL AND {range}
+            GROUP BY name, artist_name
+            ORDER BY plays DESC, listened_secs DESC
+            LIMIT ?3",
+            name = name,
+            artist = artist,
+            range = RANGE,
+        ))?;
+        let counts = statement
+            .query_map(params![from, to, limit], |row| {
+                Ok(Count {
+                    name: row.get("name")?,
+                    artist: row.get("artist_name")?,
+                    plays: row.get("plays")?,
+                    listened_secs: row.get("listened_secs")?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(counts)
+    }
+}
+
+pub fn export_csv(entries: &[Entry], writer: impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+
+    writer.write_record([
+        "started_at",
+        "listened_secs",
+        "duration_secs",
+        "file",
+        "title",
+        "artist",
+        "album",
+        "album_artist",
+        "skipped",
+    ])?;
+
+    for entry in entries {
+        writer.write_record([
+            entry.started_at.to_string(),
+            entry.listened_secs.to_string(),
+            entry
+                .duration_secs
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            entry.file.clone(),
+            entry.title.clone().unwrap_or_default(),
+            entry.artist.clone().unwrap_or_default(),
+            entry.album.clone().unwrap_or_default(),
+            entry.album_artist.clone().unwrap_or_default(),
+            entry.skipped.to_string(),
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn export_jsonl(entries: &[Entry], mut writer: impl Write) -> Result<()> {
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Writes the listens that weren't skipped as a JSON array, as accepted by ListenBrainz's
+/// import.
+pub fn export_listenbrainz(entries: &[Entry], writer: impl Write) -> Result<()> {
+    let listens: Vec<_> = entries
+        .iter()
+        .filter(|x| !x.skipped)
+        .filter_map(Entry::to_listen)
+        .collect();
+    serde_json::to_writer_pretty(writer, &listens)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd::test_util::song;
+
+    fn history() -> History {
+        History::open(&HistoryConfig {
+            path: ":memory:".into(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn filters_by_date() {
+        let history = history();
+        history
+            .connection
+            .execute(
+                "INSERT INTO listens (started_at, listened_ms, file, tags, skipped)
+                VALUES (1700000000, 0, 'a.flac', '{}', 0)",
+                [],
+            )
+            .unwrap();
+
+        assert_eq!(history.range(Some("2023-11-14"), None).unwrap().len(), 1);
+        assert_eq!(history.range(Some("2023-11-15"), None).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        let history = history();
+        assert!(history.range(Some("last tuesday"), None).is_err());
+        assert!(history
+            .top(TopKind::Artists, None, Some("2024-13-45"), 10)
+            .is_err());
+    }
+
+    /// Records a listen of `title` by `artist`, starting `offset` seconds into November 2023.
+    fn record(history: &History, title: &str, artist: &str, offset: u64, skipped: bool) {
+        let song = song(&format!(
+            "file: {}.flac\nTitle: {}\nArtist: {}\nAlbum: Album\nduration: 180.000\n",
+            title, title, artist
+        ));
+        let started_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000 + offset);
+        history
+            .record(&song, started_at, Duration::from_secs(90), skipped)
+            .unwrap();
+    }
+
+    #[test]
+    fn records_listens() {
+        let history = history();
+        record(&history, "First", "Artist", 0, false);
+        record(&history, "Second", "Artist", 60, true);
+
+        let entries = history.recent(10).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].title.as_deref(), Some("Second"));
+        assert!(entries[0].skipped);
+
+        let entry = &entries[1];
+        assert_eq!(entry.started_at, 1_700_000_000);
+        assert_eq!(entry.listened_secs, 90);
+        assert_eq!(entry.duration_secs, Some(180));
+        assert_eq!(entry.file, "First.flac");
+        assert_eq!(entry.artist.as_deref(), Some("Artist"));
+        assert_eq!(entry.album.as_deref(), Some("Album"));
+        assert_eq!(entry.tags["Title"], ["First"]);
+        assert!(!entry.skipped);
+    }
+
+    #[test]
+    fn counts_top_listens() {
+        let history = history();
+        record(&history, "Often", "Band", 0, false);
+        record(&history, "Often", "Band", 10, false);
+        record(&history, "Once", "Singer", 20, false);
+        record(&history, "Skipped", "Singer", 30, true);
+        record(&history, "Skipped", "Singer", 40, true);
+
+        let artists = history.top(TopKind::Artists, None, None, 10).unwrap();
+        let artists: Vec<_> = artists.iter().map(|x| (&*x.name, x.plays)).collect();
+        assert_eq!(artists, [("Band", 2), ("Singer", 1)]);
+
+        let tracks = history.top(TopKind::Tracks, None, None, 1).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].name, "Often");
+        assert_eq!(tracks[0].artist.as_deref(), Some("Band"));
+        assert_eq!(tracks[0].listened_secs, 180);
+    }
+
+    #[test]
+    fn exports() {
+        let history = history();
+        record(&history, "First", "Artist", 0, false);
+        record(&history, "Second", "Artist", 60, true);
+        let entries = history.range(None, None).unwrap();
+
+        let mut csv = vec![];
+        export_csv(&entries, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "started_at,listened_secs,duration_secs,file,title,artist,album,album_artist,skipped\n\
+             1700000000,90,180,First.flac,First,Artist,Album,,false\n\
+             1700000060,90,180,Second.flac,Second,Artist,Album,,true\n"
+        );
+
+        let mut jsonl = vec![];
+        export_jsonl(&entries, &mut jsonl).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(jsonl)
+            .unwrap()
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["title"], "Second");
+        assert_eq!(lines[1]["skipped"], true);
+
+        let mut listens = vec![];
+        export_listenbrainz(&entries, &mut listens).unwrap();
+        let listens: serde_json::Value = serde_json::from_slice(&listens).unwrap();
+        assert_eq!(listens.as_array().unwrap().len(), 1);
+        assert_eq!(listens[0]["listened_at"], 1_700_000_000);
+        assert_eq!(listens[0]["track_metadata"]["track_name"], "First");
+    }
+}
@@ -0,0 +1,220 @@
+use super::config::LastFmConfig;
+use super::conversions::get_artist;
+use super::mpd::Song;
+use anyhow::{anyhow, bail, Context, Result};
+use mpd_client::tag::Tag;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most scrobbles Last.fm accepts in a single request.
+pub const MAX_BATCH: usize = 50;
+
+/// An error caused by what was submitted, which won't go away by trying again.
+#[derive(Debug)]
+pub struct Rejected {
+    pub code: u64,
+    pub message: String,
+}
+
+impl Rejected {
+    /// Invalid parameters, invalid resource and invalid signature, the last of which can be
+    /// caused by text Last.fm doesn't like. Other errors are about the service or the session.
+    const CODES: [u64; 3] = [6, 7, 13];
+}
+
+impl Display for Rejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "rejected with error {}: {}", self.code, self.message)
+    }
+}
+
+impl Error for Rejected {}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Scrobble {
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u64>,
+    pub duration: Option<u64>,
+    pub timestamp: u64,
+}
+
+impl Scrobble {
+    pub fn from_song(song: &Song, started_at: SystemTime) -> Option<Self> {
+        let track_number = song
+            .tags
+            .get(&Tag::Track)
+            .and_then(|x| x.first())
+            .and_then(|x| x.split('/').next()?.parse().ok());
+
+        Some(Self {
+            artist: get_artist(song)?,
+            track: song.title()?.to_string(),
+            album: song.album().map(str::to_string),
+            album_artist: song.album_artists().first().cloned(),
+            track_number,
+            duration: song.duration.map(|x| x.as_secs()),
+            timestamp: started_at.duration_since(UNIX_EPOCH).ok()?.as_secs(),
+        })
+    }
+
+    fn params(&self, suffix: &str, params: &mut Vec<(String, String)>) {
+        let mut push =
+            |name: &str, value: String| params.push((format!("{}{}", name, suffix), value));
+
+        push("artist", self.artist.clone());
+        push("track", self.track.clone());
+        push("timestamp", self.timestamp.to_string());
+
+        if let Some(album) = &self.album {
+            push("album", album.clone());
+        }
+
+        if let Some(album_artist) = &self.album_artist {
+            push("albumArtist", album_artist.clone());
+        }
+
+        if let Some(track_number) = self.track_number {
+            push("trackNumber", track_number.to_string());
+        }
+
+        if let Some(duration) = self.duration {
+            push("duration", duration.to_string());
+        }
+    }
+}
+
+pub struct LastFm {
+    client: Client,
+    api_base: String,
+    api_key: String,
+    api_secret: String,
+    session_key: Option<String>,
+}
+
+impl LastFm {
+    pub fn new(config: &LastFmConfig) -> Self {
+        Self {
+            client: Client::new(),
+            api_base: config.api_base.clone(),
+            api_key: config.api_key.clone(),
+            api_secret: config.api_secret.clone(),
+            session_key: config.session_key.clone(),
+        }
+    }
+
+    fn sign(&self, params: &mut Vec<(String, String)>) {
+        params.sort();
+
+        let mut signature = String::new();
+        for (name, value) in params.iter() {
+            signature.push_str(name);
+            signature.push_str(value);
+        }
+        signature.push_str(&self.api_secret);
+
+        let signature = format!("{:x}", md5::compute(signature));
+        params.push(("api_sig".into(), signature));
+    }
+
+    async fn call(&self, method: &str, mut params: Vec<(String, String)>) -> Result<Value> {
+        params.push(("method".into(), method.into()));
+        params.push(("api_key".into(), self.api_key.clone()));
+        self.sign(&mut params);
+        params.push(("format".into(), "json".into()));
+
+        let response = self
+            .client
+            .post(&self.api_base)
+            .form(&params)
+            .send()
+            .await?;
+        let status = response.status();
+
+        let body: Value = match response.json().await {
+            Ok(body) => body,
+            Err(_) if !status.is_success() => bail!("{} failed with {}", method, status),
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(code) = body.get("error") {
+            let message = body.get("message").and_then(Value::as_str).unwrap_or("");
+
+            if let Some(code) = code.as_u64().filter(|x| Rejected::CODES.contains(x)) {
+                let message = message.to_string();
+                return Err(
+                    anyhow!(Rejected { code, message }).context(format!("{} failed", method))
+                );
+            }
+
+            bail!("{} failed with error {}: {}", method, code, message);
+        }
+
+        Ok(body)
+    }
+
+    async fn call_authenticated(
+        &self,
+        method: &str,
+        mut params: Vec<(String, String)>,
+    ) -> Result<Value> {
+        let session_key = self
+            .session_key
+            .clone()
+            .context("missing last.fm session key, run lastfm-auth first")?;
+        params.push(("sk".into(), session_key));
+        self.call(method, params).await
+    }
+
+    pub async fn get_token(&self) -> Result<String> {
+        let body = self.call("auth.getToken", vec![]).await?;
+        body["token"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("no token in response"))
+    }
+
+    pub fn auth_url(&self, token: &str) -> String {
+        format!(
+            "https://www.last.fm/api/auth/?api_key={}&token={}",
+            self.api_key, token
+        )
+    }
+
+    pub async fn get_session(&self, token: &str) -> Result<String> {
+        let body = self
+            .call("auth.getSession", vec![("token".into(), token.into())])
+            .await?;
+        body["session"]["key"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("no session key in response"))
+    }
+
+    pub async fn update_now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        let mut params = vec![];
+        scrobble.params("", &mut params);
+        // Now playing notifications don't take a timestamp.
+        params.retain(|(name, _)| name != "timestamp");
+
+        self.call_authenticated("track.updateNowPlaying", params)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn scrobble(&self, scrobbles: &[Scrobble]) -> Result<()> {
+        let mut params = vec![];
+        for (i, scrobble) in scrobbles.iter().take(MAX_BATCH).enumerate() {
+            scrobble.params(&format!("[{}]", i), &mut params);
+        }
+
+        self.call_authenticated("track.scrobble", params).await?;
+        Ok(())
+    }
+}
@@ -1,30 +1,83 @@
 #![feature(never_type)]
 
+use crate::activitypub::ActivityPub;
 use crate::mpd::Mpd;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use config::Config;
 use log::{info, trace, warn};
 use mpd::SongStatus;
-use std::future::pending;
+use std::future::{pending, Future};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::broadcast;
+use tokio::task::JoinSet;
 use tokio::time::sleep;
 
+pub mod activitypub;
 pub mod art_server;
+pub mod bluesky;
 pub mod config;
 pub mod conversions;
 pub mod discord;
+pub mod github;
+pub mod history;
+pub mod lastfm;
+pub mod listen;
+pub mod listenbrainz;
 pub mod mastodon;
+pub mod matrix;
+pub mod misskey;
 pub mod mpd;
 pub mod mpd_watcher;
+pub mod mpris;
+pub mod notifications;
+pub mod profile;
+pub mod queue;
+pub mod slack;
+pub mod template;
 pub mod updaters;
+pub mod util;
+pub mod xmpp;
 
 pub type StatusTx = broadcast::Sender<SongStatus>;
 pub type StatusRx = broadcast::Receiver<SongStatus>;
 
+/// Restarts an updater whenever it fails.
+async fn supervise<F, Fut>(name: &str, mut updater: F) -> Result<!>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<!>>,
+{
+    loop {
+        let Err(err) = updater().await;
+        warn!("{}: {}", name, err);
+        sleep(Duration::from_millis(5000)).await;
+    }
+}
+
+/// Runs a separately supervised updater for each of several configured items, so that one
+/// failing doesn't hold up the others.
+async fn supervise_each<F, Fut>(names: Vec<String>, updater: F) -> Result<!>
+where
+    F: Fn(usize) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<!>> + Send + 'static,
+{
+    let updater = Arc::new(updater);
+    let mut tasks = JoinSet::new();
+
+    for (index, name) in names.into_iter().enumerate() {
+        let updater = updater.clone();
+        tasks.spawn(async move { supervise(&name, || updater(index)).await });
+    }
+
+    match tasks.join_next().await {
+        Some(Err(err)) => Err(err.into()),
+        _ => pending().await,
+    }
+}
+
 pub async fn run(config: Arc<Config>) -> Result<!> {
-    let (tx, rx) = broadcast::channel(2);
+    let (tx, rx) = broadcast::channel(16);
 
     trace!("connecting to mpd");
     let (mpd, events) = Mpd::connect().await?;
@@ -33,19 +86,195 @@ pub async fn run(config: Arc<Config>) -> Result<!> {
 
     info!("connected to mpd {}", mpd.protocol_version());
 
-    let mpd_watch = mpd_watcher::mpd_watcher(&mpd, events, tx.clone());
+    let activitypub = match (&config.activitypub, &config.web) {
+        (Some(activitypub_config), Some(web_config)) => Some(Arc::new(
+            ActivityPub::open(activitypub_config, web_config).await?,
+        )),
+        (Some(_), None) => bail!("the ActivityPub actor needs the web server to be configured"),
+        (None, _) => None,
+    };
+
+    let mpd_watch = mpd_watcher::mpd_watcher(&mpd, events, tx.clone(), &config);
     let discord_thread = updaters::discord::discord_updater(config.clone(), rx);
-    let mastodon = async {
-        loop {
-            let Err(err) =
-                updaters::mastodon::mastodon_updater(config.clone(), tx.subscribe()).await;
-            warn!("{}", err);
-            sleep(Duration::from_millis(5000)).await;
+    let mastodon = {
+        let (config, mpd, tx) = (config.clone(), mpd.clone(), tx.clone());
+        let names = config.mastodon.iter().map(|x| x.instance.clone()).collect();
+
+        supervise_each(names, move |index| {
+            updaters::mastodon::mastodon_updater(config.clone(), index, mpd.clone(), tx.subscribe())
+        })
+    };
+    let webhooks = {
+        let (config, tx) = (config.clone(), tx.clone());
+        let names = config.webhook.iter().map(|x| x.url.clone()).collect();
+
+        supervise_each(names, move |index| {
+            updaters::webhook::webhook_updater(config.clone(), index, tx.subscribe())
+        })
+    };
+    let icecast = {
+        let (config, tx) = (config.clone(), tx.clone());
+        let names = config.icecast.iter().map(|x| x.url.clone()).collect();
+
+        supervise_each(names, move |index| {
+            updaters::icecast::icecast_updater(config.clone(), index, tx.subscribe())
+        })
+    };
+    let files = {
+        let (config, mpd, tx) = (config.clone(), mpd.clone(), tx.clone());
+        let names = config
+            .file
+            .iter()
+            .map(|x| x.path.display().to_string())
+            .collect();
+
+        supervise_each(names, move |index| {
+            updaters::file::file_updater(config.clone(), index, mpd.clone(), tx.subscribe())
+        })
+    };
+    let exec = async {
+        if let Some(exec_config) = &config.exec {
+            supervise("exec", || {
+                updaters::exec::exec_updater(&config, exec_config, &mpd, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let github = async {
+        if let Some(github_config) = &config.github {
+            supervise("github", || {
+                updaters::github::github_updater(&config, github_config, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let history = async {
+        if let Some(history_config) = &config.history {
+            supervise("history", || {
+                updaters::history::history_updater(history_config, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let irc = async {
+        if let Some(irc_config) = &config.irc {
+            supervise("irc", || {
+                updaters::irc::irc_updater(&config, irc_config, &mpd, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let lastfm = async {
+        if let Some(lastfm_config) = &config.lastfm {
+            supervise("last.fm", || {
+                updaters::lastfm::lastfm_updater(lastfm_config, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let listenbrainz = async {
+        if let Some(listenbrainz_config) = &config.listenbrainz {
+            supervise("listenbrainz", || {
+                updaters::listenbrainz::listenbrainz_updater(listenbrainz_config, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let matrix = async {
+        if let Some(matrix_config) = &config.matrix {
+            supervise("matrix", || {
+                updaters::matrix::matrix_updater(&config, matrix_config, &mpd, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let mpris = async {
+        if let Some(mpris_config) = &config.mpris {
+            supervise("mpris", || {
+                updaters::mpris::mpris_updater(&config, mpris_config, mpd.clone(), tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let mqtt = async {
+        if let Some(mqtt_config) = &config.mqtt {
+            supervise("mqtt", || {
+                updaters::mqtt::mqtt_updater(&config, mqtt_config, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let notifications = async {
+        if let Some(notifications_config) = &config.notifications {
+            supervise("notifications", || {
+                updaters::notifications::notifications_updater(
+                    notifications_config,
+                    &mpd,
+                    tx.subscribe(),
+                )
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let slack = async {
+        if let Some(slack_config) = &config.slack {
+            supervise("slack", || {
+                updaters::slack::slack_updater(&config, slack_config, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let xmpp = async {
+        if let Some(xmpp_config) = &config.xmpp {
+            supervise("xmpp", || {
+                updaters::xmpp::xmpp_updater(xmpp_config, tx.subscribe())
+            })
+            .await
+        } else {
+            pending().await
+        }
+    };
+    let activitypub_publisher = async {
+        match (&activitypub, &config.activitypub) {
+            (Some(activitypub), Some(activitypub_config)) => {
+                supervise("activitypub", || {
+                    updaters::activitypub::activitypub_updater(
+                        activitypub,
+                        activitypub_config,
+                        &config,
+                        tx.subscribe(),
+                    )
+                })
+                .await
+            }
+            _ => pending().await,
         }
     };
     let art_server = async {
         if let Some(web_config) = &config.web {
-            art_server::serve(web_config, mpd.clone()).await
+            art_server::serve(web_config, mpd.clone(), activitypub.clone()).await
         } else {
             pending().await
         }
@@ -55,6 +284,22 @@ pub async fn run(config: Arc<Config>) -> Result<!> {
         mpd_error = mpd_watch => mpd_error,
         discord_err = discord_thread => discord_err,
         mastodon_err = mastodon => mastodon_err,
+        webhook_err = webhooks => webhook_err,
+        icecast_err = icecast => icecast_err,
+        file_err = files => file_err,
+        exec_err = exec => exec_err,
+        github_err = github => github_err,
+        history_err = history => history_err,
+        irc_err = irc => irc_err,
+        lastfm_err = lastfm => lastfm_err,
+        listenbrainz_err = listenbrainz => listenbrainz_err,
+        matrix_err = matrix => matrix_err,
+        mpris_err = mpris => mpris_err,
+        mqtt_err = mqtt => mqtt_err,
+        notifications_err = notifications => notifications_err,
+        slack_err = slack => slack_err,
+        xmpp_err = xmpp => xmpp_err,
+        activitypub_err = activitypub_publisher => activitypub_err,
         art_server_err = art_server => art_server_err,
     }
 }
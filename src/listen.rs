@@ -0,0 +1,158 @@
+use super::mpd::{Change, PlayState, Song, SongStatus};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// Tracks shorter than this are never counted as listened to.
+const MIN_LENGTH: Duration = Duration::from_secs(30);
+/// Playing a track for this long always counts as listening to it.
+const MAX_THRESHOLD: Duration = Duration::from_secs(4 * 60);
+
+pub enum ListenEvent {
+    /// A track started or resumed playing.
+    NowPlaying(Song),
+    /// A track has been played for long enough to count as listened to.
+    Listened {
+        song: Song,
+        started_at: SystemTime,
+        listened: Duration,
+    },
+    /// A track stopped playing, either by ending or by being skipped.
+    Finished {
+        song: Song,
+        started_at: SystemTime,
+        listened: Duration,
+        /// Whether it was stopped before being played for long enough to count.
+        skipped: bool,
+    },
+}
+
+struct Current {
+    song: Song,
+    duration: Option<Duration>,
+    started_at: SystemTime,
+    /// Playing time before the most recent resume.
+    listened: Duration,
+    playing_since: Option<Instant>,
+    submitted: bool,
+}
+
+impl Current {
+    fn listened(&self) -> Duration {
+        self.listened + self.playing_since.map_or(Duration::ZERO, |x| x.elapsed())
+    }
+
+    /// Half the track or four minutes, whichever comes first.
+    fn threshold(&self) -> Option<Duration> {
+        match self.duration {
+            Some(duration) if duration < MIN_LENGTH => None,
+            Some(duration) => Some((duration / 2).min(MAX_THRESHOLD)),
+            None => Some(MAX_THRESHOLD),
+        }
+    }
+
+    fn finish(self) -> ListenEvent {
+        let listened = self.listened();
+        let skipped = match (self.threshold(), self.duration) {
+            (Some(threshold), _) => listened < threshold,
+            (None, Some(duration)) => listened + Duration::from_secs(1) < duration,
+            (None, None) => false,
+        };
+
+        ListenEvent::Finished {
+            song: self.song,
+            started_at: self.started_at,
+            listened,
+            skipped,
+        }
+    }
+}
+
+/// Works out how long each track was actually played for, not counting time spent paused, to
+/// apply the usual scrobbling rules.
+#[derive(Default)]
+pub struct ListenTracker {
+    current: Option<Current>,
+}
+
+impl ListenTracker {
+    pub fn update(&mut self, song_status: &SongStatus) -> Vec<ListenEvent> {
+        let playing = song_status.status.state == PlayState::Playing;
+        let mut events = vec![];
+
+        match song_status.change {
+            Change::TrackChanged => {
+                events.extend(self.current.take().map(Current::finish));
+
+                let song = match song_status.song.clone() {
+                    Some(song) => song,
+                    None => return events,
+                };
+                let started_at = song_status
+                    .position
+                    .map_or_else(SystemTime::now, |x| x.started_at());
+
+                self.current = Some(Current {
+                    song: song.clone(),
+                    duration: song_status.status.duration.or(song.duration),
+                    started_at,
+                    listened: Duration::ZERO,
+                    playing_since: playing.then(Instant::now),
+                    submitted: false,
+                });
+
+                if playing {
+                    events.push(ListenEvent::NowPlaying(song));
+                }
+            }
+            Change::Paused => {
+                if let Some(current) = &mut self.current {
+                    if let Some(since) = current.playing_since.take() {
+                        current.listened += since.elapsed();
+                    }
+                }
+            }
+            Change::Resumed => {
+                if let Some(current) = &mut self.current {
+                    current.playing_since.get_or_insert_with(Instant::now);
+                    events.push(ListenEvent::NowPlaying(current.song.clone()));
+                }
+            }
+            Change::Seeked => {}
+            Change::Stopped => {
+                events.extend(self.current.take().map(Current::finish));
+            }
+        }
+
+        events
+    }
+
+    /// When the current track will have been played for long enough, if it's playing.
+    pub fn deadline(&self) -> Option<Instant> {
+        let current = self.current.as_ref()?;
+        let since = current.playing_since?;
+
+        if current.submitted {
+            return None;
+        }
+
+        let remaining = current.threshold()?.saturating_sub(current.listened);
+        Some(since + remaining)
+    }
+
+    /// Returns the current track once, as soon as it has been played for long enough.
+    pub fn poll(&mut self) -> Option<ListenEvent> {
+        let current = self.current.as_mut()?;
+
+        if current.submitted || current.listened() < current.threshold()? {
+            return None;
+        }
+
+        current.submitted = true;
+
+        Some(ListenEvent::Listened {
+            song: current.song.clone(),
+            started_at: current.started_at,
+            listened: current.listened(),
+        })
+    }
+}
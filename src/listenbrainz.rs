@@ -0,0 +1,178 @@
+use super::config::ListenBrainzConfig;
+use super::conversions::get_artist;
+use super::mpd::Song;
+use anyhow::{Error, Result};
+use mpd_client::tag::Tag;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The most listens sent in a single import.
+pub const MAX_BATCH: usize = 100;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdditionalInfo {
+    pub media_player: String,
+    pub submission_client: String,
+    pub submission_client_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_mbid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_mbid: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub artist_mbids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_mbid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracknumber: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrackMetadata {
+    pub artist_name: String,
+    pub track_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_name: Option<String>,
+    pub additional_info: AdditionalInfo,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Listen {
+    /// Left out for now playing notifications.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listened_at: Option<u64>,
+    pub track_metadata: TrackMetadata,
+}
+
+impl AdditionalInfo {
+    /// Fills in what we know about a track, given a lookup of its tags by their MPD names.
+    pub fn new<'a>(
+        tags: impl Fn(&str) -> Option<&'a Vec<String>>,
+        duration: Option<Duration>,
+    ) -> Self {
+        let first = |name| tags(name).and_then(|x| x.first()).cloned();
+
+        Self {
+            media_player: "MPD".into(),
+            submission_client: "mpdiscord".into(),
+            submission_client_version: env!("CARGO_PKG_VERSION").into(),
+            recording_mbid: first("MUSICBRAINZ_TRACKID"),
+            release_mbid: first("MUSICBRAINZ_ALBUMID"),
+            artist_mbids: tags("MUSICBRAINZ_ARTISTID").cloned().unwrap_or_default(),
+            track_mbid: first("MUSICBRAINZ_RELEASETRACKID"),
+            tracknumber: first("Track"),
+            duration_ms: duration.map(|x| x.as_millis() as u64),
+        }
+    }
+}
+
+impl Listen {
+    pub fn from_song(song: &Song, listened_at: Option<SystemTime>) -> Option<Self> {
+        let listened_at = match listened_at {
+            Some(time) => Some(time.duration_since(UNIX_EPOCH).ok()?.as_secs()),
+            None => None,
+        };
+
+        let additional_info = AdditionalInfo::new(
+            |name| Tag::try_from(name).ok().and_then(|tag| song.tags.get(&tag)),
+            song.duration,
+        );
+
+        Some(Self {
+            listened_at,
+            track_metadata: TrackMetadata {
+                artist_name: get_artist(song)?,
+                track_name: song.title()?.to_string(),
+                release_name: song.album().map(str::to_string),
+                additional_info,
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Submission<'a> {
+    listen_type: &'a str,
+    payload: &'a [Listen],
+}
+
+pub struct ListenBrainz {
+    client: Client,
+    api_base: String,
+    token: String,
+}
+
+impl ListenBrainz {
+    pub fn new(config: &ListenBrainzConfig) -> Self {
+        Self {
+            client: Client::new(),
+            api_base: config.api_base.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+        }
+    }
+
+    async fn submit(&self, listen_type: &str, payload: &[Listen]) -> Result<()> {
+        self.client
+            .post(format!("{}/1/submit-listens", self.api_base))
+            .header("Authorization", format!("Token {}", self.token))
+            .json(&Submission {
+                listen_type,
+                payload,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn playing_now(&self, listen: &Listen) -> Result<()> {
+        self.submit("playing_now", std::slice::from_ref(listen))
+            .await
+    }
+
+    /// Whether submitting failed because of the listens themselves, so trying again won't help.
+    pub fn is_rejected(err: &Error) -> bool {
+        let status = err
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|x| x.status());
+        status == Some(StatusCode::BAD_REQUEST)
+    }
+
+    /// Submits a single listen as such, or several at once as an import.
+    pub async fn listens(&self, listens: &[Listen]) -> Result<()> {
+        let listens = &listens[..listens.len().min(MAX_BATCH)];
+
+        if listens.len() == 1 {
+            self.submit("single", listens).await
+        } else {
+            self.submit("import", listens).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd::test_util::song;
+
+    #[test]
+    fn listen_from_song() {
+        let song = song(
+            "file: a.flac\nArtist: Artist\nTitle: Title\nTrack: 3\n\
+             MUSICBRAINZ_TRACKID: recording\nMUSICBRAINZ_ARTISTID: first\n\
+             MUSICBRAINZ_ARTISTID: second\nduration: 61.500\nPos: 0\nId: 1\n",
+        );
+        let listen = Listen::from_song(&song, Some(UNIX_EPOCH)).unwrap();
+        let info = listen.track_metadata.additional_info;
+
+        assert_eq!(listen.listened_at, Some(0));
+        assert_eq!(listen.track_metadata.artist_name, "Artist");
+        assert_eq!(info.recording_mbid.as_deref(), Some("recording"));
+        assert_eq!(info.artist_mbids, ["first", "second"]);
+        assert_eq!(info.tracknumber.as_deref(), Some("3"));
+        assert_eq!(info.duration_ms, Some(61500));
+    }
+}
@@ -1,16 +1,121 @@
-#![feature(never_type)]
-
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use mpdiscord::config::Config;
+use mpdiscord::history::{self, History, TopKind};
+use mpdiscord::lastfm::LastFm;
 use mpdiscord::{config::read_config, run};
 use simple_logger::SimpleLogger;
 use std::env::args_os;
+use std::io::{stdin, stdout};
+use std::time::{Duration, UNIX_EPOCH};
+
+async fn lastfm_auth(config: &Config) -> Result<()> {
+    let lastfm_config = config
+        .lastfm
+        .as_ref()
+        .context("Last.fm isn't configured!")?;
+    let lastfm = LastFm::new(lastfm_config);
+
+    let token = lastfm.get_token().await?;
+    println!("Allow access at {}", lastfm.auth_url(&token));
+    println!("then press enter.");
+    stdin().read_line(&mut String::new())?;
+
+    let session_key = lastfm.get_session(&token).await?;
+    println!("Add this to the [lastfm] section of your config:");
+    println!("session_key = {:?}", session_key);
+
+    Ok(())
+}
+
+fn history_command(config: &Config, args: &[String]) -> Result<()> {
+    let history_config = config
+        .history
+        .as_ref()
+        .context("History isn't configured!")?;
+    let history = History::open(history_config)?;
+
+    let arg = |i: usize| args.get(i).map(String::as_str);
+
+    let top_kind = match arg(0) {
+        Some("recent") => {
+            let limit = arg(1).map(str::parse).transpose()?.unwrap_or(20);
+
+            for entry in history.recent(limit)? {
+                let time = UNIX_EPOCH + Duration::from_secs(entry.started_at);
+                println!(
+                    "{}  {} - {}{}",
+                    humantime::format_rfc3339_seconds(time),
+                    entry.artist.as_deref().unwrap_or("Unknown Artist"),
+                    entry.title.as_deref().unwrap_or(&entry.file),
+                    if entry.skipped { " (skipped)" } else { "" }
+                );
+            }
+
+            return Ok(());
+        }
+        Some("export") => {
+            let entries = history.range(arg(2), arg(3))?;
+
+            return match arg(1) {
+                Some("csv") => history::export_csv(&entries, stdout()),
+                Some("jsonl") => history::export_jsonl(&entries, stdout()),
+                Some("listenbrainz") => history::export_listenbrainz(&entries, stdout()),
+                _ => bail!("Expected one of csv, jsonl or listenbrainz!"),
+            };
+        }
+        Some("top-artists") => TopKind::Artists,
+        Some("top-albums") => TopKind::Albums,
+        Some("top-tracks") => TopKind::Tracks,
+        _ => bail!(
+            "Usage: history recent [count] | top-artists|top-albums|top-tracks [from [to]] | \
+             export csv|jsonl|listenbrainz [from [to]]"
+        ),
+    };
+
+    for (i, count) in history
+        .top(top_kind, arg(1), arg(2), 25)?
+        .iter()
+        .enumerate()
+    {
+        let by = count
+            .artist
+            .as_ref()
+            .map(|x| format!(" by {}", x))
+            .unwrap_or_default();
+        println!(
+            "{:>3}. {}{} ({} plays, {} minutes)",
+            i + 1,
+            count.name,
+            by,
+            count.plays,
+            count.listened_secs / 60
+        );
+    }
+
+    Ok(())
+}
 
 #[tokio::main]
-async fn main() -> Result<!> {
+async fn main() -> Result<()> {
     SimpleLogger::new().with_utc_timestamps().init()?;
 
-    let config_path = args_os().nth(1).context("Missing configuration path!")?;
+    let mut args = args_os().skip(1);
+    let config_path = args.next().context("Missing configuration path!")?;
     let config = read_config(&config_path).await?;
 
-    run(config).await
+    // Only the configuration path can be any file name, the rest has to be text.
+    let args = args
+        .map(|x| x.into_string())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|x| anyhow!("Invalid argument {:?}!", x))?;
+
+    match args.first().map(String::as_str) {
+        None => {
+            let Err(err) = run(config).await;
+            Err(err)
+        }
+        Some("lastfm-auth") => lastfm_auth(&config).await,
+        Some("history") => history_command(&config, &args[1..]),
+        Some(command) => bail!("Unknown command {:?}!", command),
+    }
 }
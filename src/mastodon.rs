@@ -1,11 +1,19 @@
-use super::config::Config;
-use anyhow::Result;
-use reqwest::Client;
+use super::config::Visibility;
+use super::profile::{Field, Profile, ProfileBackend};
+use anyhow::{bail, Result};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, StatusCode};
 use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::sleep;
 
 #[derive(Deserialize)]
 pub struct AccountSource {
+    // Pleroma leaves this out for accounts that never set a bio.
+    #[serde(default)]
     pub note: String,
+    #[serde(default)]
+    pub fields: Vec<Field>,
 }
 
 #[derive(Deserialize)]
@@ -14,23 +22,74 @@ pub struct Account {
     pub source: AccountSource,
 }
 
+impl From<Account> for Profile {
+    fn from(account: Account) -> Self {
+        Self {
+            acct: account.acct,
+            note: account.source.note,
+            fields: account.source.fields,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub url: Option<String>,
+}
+
+/// Servers implementing the Mastodon API, which differ in the details.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    Mastodon,
+    /// Pleroma and Akkoma.
+    Pleroma,
+    GoToSocial,
+}
+
 pub struct Mastodon {
     client: Client,
+    instance: String,
     token: String,
+    flavor: Flavor,
 }
 
 impl Mastodon {
-    pub fn new(config: &Config) -> Self {
+    pub fn new(instance: &str, token: &str, flavor: Flavor) -> Self {
         let client = Client::new();
-        let token = config.mastodon_token.clone();
-        Self { client, token }
+        Self {
+            client,
+            instance: instance.to_string(),
+            token: token.to_string(),
+            flavor,
+        }
     }
 
     pub async fn account(&self) -> Result<Account> {
         let account = self
             .client
-            .get("https://60228.dev/api/v1/accounts/verify_credentials")
+            .get(format!(
+                "{}/api/v1/accounts/verify_credentials",
+                self.instance
+            ))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(account)
+    }
+
+    async fn update_credentials<T: serde::Serialize + ?Sized>(&self, form: &T) -> Result<Account> {
+        let account = self
+            .client
+            .patch(format!(
+                "{}/api/v1/accounts/update_credentials",
+                self.instance
+            ))
             .bearer_auth(&self.token)
+            .form(form)
             .send()
             .await?
             .error_for_status()?
@@ -39,14 +98,237 @@ impl Mastodon {
         Ok(account)
     }
 
-    pub async fn set_bio(&self, bio: &str) -> Result<()> {
+    pub async fn set_bio(&self, bio: &str) -> Result<Account> {
+        self.update_credentials(&[("note", bio)]).await
+    }
+
+    /// Replaces all profile metadata fields, leaving the note untouched.
+    pub async fn set_fields(&self, fields: &[Field]) -> Result<Account> {
+        let mut form = vec![];
+        for (i, field) in fields.iter().enumerate() {
+            form.push((
+                format!("fields_attributes[{}][name]", i),
+                field.name.as_str(),
+            ));
+            form.push((
+                format!("fields_attributes[{}][value]", i),
+                field.value.as_str(),
+            ));
+        }
+
+        self.update_credentials(&form).await
+    }
+
+    /// Uploads an attachment, waiting for the server to finish processing it.
+    pub async fn upload_media(&self, data: Vec<u8>, mime: Option<&str>) -> Result<String> {
+        let mut part = Part::bytes(data).file_name("cover");
+        if let Some(mime) = mime {
+            part = part.mime_str(mime)?;
+        }
+
+        // Pleroma only has the synchronous v1 endpoint.
+        let endpoint = match self.flavor {
+            Flavor::Pleroma => "api/v1/media",
+            _ => "api/v2/media",
+        };
+
+        let response = self
+            .client
+            .post(format!("{}/{}", self.instance, endpoint))
+            .bearer_auth(&self.token)
+            .multipart(Form::new().part("file", part))
+            .send()
+            .await?
+            .error_for_status()?;
+        let processing = response.status() == StatusCode::ACCEPTED;
+        let mut attachment: Attachment = response.json().await?;
+
+        if processing {
+            for _ in 0..10 {
+                if attachment.url.is_some() {
+                    return Ok(attachment.id);
+                }
+
+                sleep(Duration::from_secs(1)).await;
+
+                attachment = self
+                    .client
+                    .get(format!("{}/api/v1/media/{}", self.instance, attachment.id))
+                    .bearer_auth(&self.token)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+            }
+
+            bail!("media {} is still processing", attachment.id);
+        }
+
+        Ok(attachment.id)
+    }
+
+    pub async fn post_status(
+        &self,
+        status: &str,
+        visibility: Visibility,
+        media_ids: &[String],
+    ) -> Result<()> {
+        let mut form = vec![("status", status), ("visibility", visibility.as_str())];
+        form.extend(media_ids.iter().map(|id| ("media_ids[]", id.as_str())));
+
         self.client
-            .patch("https://60228.dev/api/v1/accounts/update_credentials")
+            .post(format!("{}/api/v1/statuses", self.instance))
             .bearer_auth(&self.token)
-            .form(&[("note", bio)])
+            .form(&form)
             .send()
             .await?
             .error_for_status()?;
         Ok(())
     }
 }
+
+#[async_trait::async_trait]
+impl ProfileBackend for Mastodon {
+    async fn profile(&self) -> Result<Profile> {
+        Ok(self.account().await?.into())
+    }
+
+    async fn set_note(&self, note: &str) -> Result<Profile> {
+        Ok(self.set_bio(note).await?.into())
+    }
+
+    async fn set_fields(&self, fields: &[Field]) -> Result<Profile> {
+        Ok(Mastodon::set_fields(self, fields).await?.into())
+    }
+
+    async fn upload_media(&self, data: Vec<u8>, mime: Option<&str>) -> Result<String> {
+        Mastodon::upload_media(self, data, mime).await
+    }
+
+    async fn post(&self, text: &str, visibility: Visibility, media_ids: &[String]) -> Result<()> {
+        self.post_status(text, visibility, media_ids).await
+    }
+
+    fn max_note(&self) -> usize {
+        match self.flavor {
+            Flavor::Mastodon => 500,
+            Flavor::Pleroma | Flavor::GoToSocial => 5000,
+        }
+    }
+
+    fn max_fields(&self) -> usize {
+        match self.flavor {
+            Flavor::Mastodon => 4,
+            Flavor::Pleroma => 10,
+            Flavor::GoToSocial => 6,
+        }
+    }
+
+    fn max_field_value(&self) -> usize {
+        match self.flavor {
+            Flavor::Mastodon | Flavor::GoToSocial => 255,
+            Flavor::Pleroma => 2048,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_util::mock_server;
+    use axum::extract::Form;
+    use axum::http::HeaderMap;
+    use axum::routing::{get, patch};
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+
+    fn account(note: &str, fields: Value) -> Json<Value> {
+        Json(json!({
+            "acct": "user",
+            "source": { "note": note, "fields": fields },
+        }))
+    }
+
+    async fn update_credentials(
+        headers: HeaderMap,
+        Form(form): Form<Vec<(String, String)>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        if headers["authorization"] != "Bearer token" {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        let value = |key: &str| form.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+
+        if let Some(note) = value("note") {
+            return Ok(account(&note, json!([])));
+        }
+
+        let mut fields = vec![];
+        while let Some(name) = value(&format!("fields_attributes[{}][name]", fields.len())) {
+            let value = value(&format!("fields_attributes[{}][value]", fields.len())).unwrap();
+            fields.push(json!({ "name": name, "value": value }));
+        }
+        Ok(account("", json!(fields)))
+    }
+
+    async fn server() -> String {
+        let app = Router::new()
+            .route(
+                "/api/v1/accounts/verify_credentials",
+                get(|| async { account("Hello", json!([])) }),
+            )
+            .route(
+                "/api/v1/accounts/update_credentials",
+                patch(update_credentials),
+            );
+        mock_server(app).await
+    }
+
+    #[tokio::test]
+    async fn profile() {
+        let mastodon = Mastodon::new(&server().await, "token", Flavor::Mastodon);
+        let profile = mastodon.profile().await.unwrap();
+        assert_eq!(profile.acct, "user");
+        assert_eq!(profile.note, "Hello");
+    }
+
+    #[tokio::test]
+    async fn set_note() {
+        let mastodon = Mastodon::new(&server().await, "token", Flavor::Mastodon);
+        let profile = ProfileBackend::set_note(&mastodon, "New bio")
+            .await
+            .unwrap();
+        assert_eq!(profile.note, "New bio");
+    }
+
+    #[tokio::test]
+    async fn set_field() {
+        let mastodon = Mastodon::new(&server().await, "token", Flavor::Mastodon);
+        let fields = [
+            Field {
+                name: "Website".into(),
+                value: "https://example.com".into(),
+            },
+            Field {
+                name: "Now playing".into(),
+                value: "Song by Artist".into(),
+            },
+        ];
+        let profile = ProfileBackend::set_fields(&mastodon, &fields)
+            .await
+            .unwrap();
+        assert!(profile.fields == fields);
+    }
+
+    #[tokio::test]
+    async fn error_response() {
+        let mastodon = Mastodon::new(&server().await, "expired", Flavor::Mastodon);
+        let err = ProfileBackend::set_note(&mastodon, "New bio")
+            .await
+            .err()
+            .unwrap();
+        let err = err.downcast::<reqwest::Error>().unwrap();
+        assert_eq!(err.status(), Some(StatusCode::UNAUTHORIZED));
+    }
+}
@@ -0,0 +1,295 @@
+use super::config::MatrixConfig;
+use anyhow::{bail, Result};
+use log::*;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+/// How many times a rate limited request is retried before giving up.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    errcode: String,
+    #[serde(default)]
+    error: String,
+    retry_after_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: String,
+}
+
+#[derive(Serialize)]
+struct Presence<'a> {
+    presence: &'a str,
+    status_msg: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Uploaded {
+    content_uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "msgtype")]
+pub enum Message<'a> {
+    #[serde(rename = "m.text")]
+    Text { body: &'a str },
+    #[serde(rename = "m.image")]
+    Image {
+        body: &'a str,
+        url: &'a str,
+        info: ImageInfo<'a>,
+    },
+}
+
+#[derive(Serialize)]
+pub struct ImageInfo<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<&'a str>,
+    pub size: usize,
+}
+
+/// Client-server API client, authenticated with an access token.
+pub struct Matrix {
+    client: Client,
+    homeserver: Url,
+    access_token: String,
+    txn_counter: u64,
+}
+
+impl Matrix {
+    pub fn new(config: &MatrixConfig) -> Result<Self> {
+        let homeserver = Url::parse(&config.homeserver)?;
+        if homeserver.cannot_be_a_base() {
+            bail!("invalid homeserver URL {:?}", config.homeserver);
+        }
+
+        Ok(Self {
+            client: Client::new(),
+            homeserver,
+            access_token: config.access_token.clone(),
+            txn_counter: 0,
+        })
+    }
+
+    /// Builds an endpoint URL, escaping each segment since IDs contain `@`, `!` and `:`.
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .expect("checked in new")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// Sends the request, waiting and retrying as long as the homeserver says we're rate limited.
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let request = request.bearer_auth(&self.access_token);
+        let mut retries = 0;
+
+        loop {
+            let response = request
+                .try_clone()
+                .expect("request bodies are buffered")
+                .send()
+                .await?;
+
+            if response.status().is_success() {
+                break Ok(response);
+            }
+
+            let status = response.status();
+            let error: ErrorResponse = match response.json().await {
+                Ok(error) => error,
+                Err(_) => bail!("homeserver responded with {}", status),
+            };
+
+            if status == StatusCode::TOO_MANY_REQUESTS
+                && error.errcode == "M_LIMIT_EXCEEDED"
+                && retries < MAX_RATE_LIMIT_RETRIES
+            {
+                let retry_after = error
+                    .retry_after_ms
+                    .map_or(DEFAULT_RETRY_AFTER, Duration::from_millis);
+                debug!("rate limited, retrying in {:?}", retry_after);
+                sleep(retry_after).await;
+                retries += 1;
+                continue;
+            }
+
+            bail!("{}: {}", error.errcode, error.error);
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    pub async fn whoami(&self) -> Result<String> {
+        let url = self.url(&["_matrix", "client", "v3", "account", "whoami"]);
+        let whoami: WhoAmI = self.call(self.client.get(url)).await?;
+        Ok(whoami.user_id)
+    }
+
+    pub async fn set_presence(
+        &self,
+        user_id: &str,
+        presence: &str,
+        status_msg: &str,
+    ) -> Result<()> {
+        let url = self.url(&["_matrix", "client", "v3", "presence", user_id, "status"]);
+        let body = Presence {
+            presence,
+            status_msg,
+        };
+        self.send(self.client.put(url).json(&body)).await?;
+        Ok(())
+    }
+
+    /// Uploads to the media repository, returning the `mxc://` URI.
+    pub async fn upload(&self, data: Vec<u8>, mime: Option<&str>) -> Result<String> {
+        let mut url = self.url(&["_matrix", "media", "v3", "upload"]);
+        url.query_pairs_mut().append_pair("filename", "cover");
+
+        let request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, mime.unwrap_or("application/octet-stream"))
+            .body(data);
+        let uploaded: Uploaded = self.call(request).await?;
+        Ok(uploaded.content_uri)
+    }
+
+    pub async fn send_message(&mut self, room_id: &str, message: &Message<'_>) -> Result<()> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let txn_id = format!("mpdiscord-{}-{}", since_epoch.as_millis(), self.txn_counter);
+        self.txn_counter += 1;
+
+        let url = self.url(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            room_id,
+            "send",
+            "m.room.message",
+            &txn_id,
+        ]);
+        self.send(self.client.put(url).json(message)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::util::test_util::mock_server;
+    use axum::extract::Path;
+    use axum::http::HeaderMap;
+    use axum::routing::put;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// A homeserver that records the path and body of every request, and rate limits the first
+    /// `limited` of them.
+    async fn homeserver(limited: usize) -> (Matrix, Requests) {
+        let requests = Requests::default();
+        let handler = {
+            let requests = requests.clone();
+            move |Path(path): Path<String>, headers: HeaderMap, Json(body): Json<Value>| async move {
+                assert_eq!(headers["authorization"], "Bearer token");
+                let mut requests = requests.lock().unwrap();
+                requests.push((path, body));
+
+                if requests.len() <= limited {
+                    let error = json!({ "errcode": "M_LIMIT_EXCEEDED", "retry_after_ms": 10 });
+                    (StatusCode::TOO_MANY_REQUESTS, Json(error))
+                } else {
+                    (StatusCode::OK, Json(json!({})))
+                }
+            }
+        };
+        let app = Router::new().route("/_matrix/client/v3/*path", put(handler));
+
+        let config = test_util::config(&format!(
+            "[matrix]\nhomeserver = \"{}\"\naccess_token = \"token\"\n",
+            mock_server(app).await
+        ));
+        (
+            Matrix::new(config.matrix.as_ref().unwrap()).unwrap(),
+            requests,
+        )
+    }
+
+    #[tokio::test]
+    async fn sets_presence() {
+        let (matrix, requests) = homeserver(0).await;
+        matrix
+            .set_presence("@user:example.org", "online", "Title - Artist")
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "presence/@user:example.org/status");
+        assert_eq!(
+            requests[0].1,
+            json!({ "presence": "online", "status_msg": "Title - Artist" })
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_messages_with_new_transaction_ids() {
+        let (mut matrix, requests) = homeserver(0).await;
+        for _ in 0..2 {
+            let message = Message::Text { body: "Title" };
+            matrix
+                .send_message("!room:example.org", &message)
+                .await
+                .unwrap();
+        }
+
+        let requests = requests.lock().unwrap();
+        let prefix = "rooms/!room:example.org/send/m.room.message/";
+        assert!(requests[0].0.starts_with(prefix), "{}", requests[0].0);
+        assert_eq!(
+            requests[0].1,
+            json!({ "msgtype": "m.text", "body": "Title" })
+        );
+        assert_ne!(requests[0].0, requests[1].0);
+    }
+
+    #[tokio::test]
+    async fn retries_when_rate_limited() {
+        let (matrix, requests) = homeserver(2).await;
+        matrix
+            .set_presence("@user:example.org", "online", "")
+            .await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_rate_limited_for_too_long() {
+        let (matrix, requests) = homeserver(usize::MAX).await;
+        let err = matrix
+            .set_presence("@user:example.org", "online", "")
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("M_LIMIT_EXCEEDED"), "{}", err);
+        let attempts = MAX_RATE_LIMIT_RETRIES as usize + 1;
+        assert_eq!(requests.lock().unwrap().len(), attempts);
+    }
+}
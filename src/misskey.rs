@@ -0,0 +1,266 @@
+use super::config::Visibility;
+use super::profile::{Field, Profile, ProfileBackend};
+use anyhow::Result;
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct MeDetailed {
+    username: String,
+    host: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    fields: Vec<Field>,
+}
+
+impl From<MeDetailed> for Profile {
+    fn from(me: MeDetailed) -> Self {
+        let acct = match me.host {
+            Some(host) => format!("{}@{}", me.username, host),
+            None => me.username,
+        };
+
+        Self {
+            acct,
+            note: me.description.unwrap_or_default(),
+            fields: me.fields,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DriveFile {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct UpdateDescription<'a> {
+    i: &'a str,
+    description: &'a str,
+}
+
+#[derive(Serialize)]
+struct UpdateFields<'a> {
+    i: &'a str,
+    fields: &'a [Field],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateNote<'a> {
+    i: &'a str,
+    text: &'a str,
+    visibility: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_ids: Option<&'a [String]>,
+}
+
+/// Client for Misskey and its forks, such as Sharkey.
+pub struct Misskey {
+    client: Client,
+    instance: String,
+    token: String,
+}
+
+impl Misskey {
+    pub fn new(instance: &str, token: &str) -> Self {
+        let client = Client::new();
+        Self {
+            client,
+            instance: instance.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        body: &(impl Serialize + ?Sized),
+    ) -> Result<T> {
+        let response = self
+            .client
+            .post(format!("{}/api/{}", self.instance, endpoint))
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    async fn me(&self) -> Result<MeDetailed> {
+        #[derive(Serialize)]
+        struct Credentials<'a> {
+            i: &'a str,
+        }
+
+        self.call("i", &Credentials { i: &self.token }).await
+    }
+}
+
+fn visibility_name(visibility: Visibility) -> &'static str {
+    match visibility {
+        Visibility::Public => "public",
+        Visibility::Unlisted => "home",
+        Visibility::Private => "followers",
+    }
+}
+
+#[async_trait::async_trait]
+impl ProfileBackend for Misskey {
+    async fn profile(&self) -> Result<Profile> {
+        Ok(self.me().await?.into())
+    }
+
+    async fn set_note(&self, note: &str) -> Result<Profile> {
+        let body = UpdateDescription {
+            i: &self.token,
+            description: note,
+        };
+        let me: MeDetailed = self.call("i/update", &body).await?;
+        Ok(me.into())
+    }
+
+    async fn set_fields(&self, fields: &[Field]) -> Result<Profile> {
+        let body = UpdateFields {
+            i: &self.token,
+            fields,
+        };
+        let me: MeDetailed = self.call("i/update", &body).await?;
+        Ok(me.into())
+    }
+
+    async fn upload_media(&self, data: Vec<u8>, mime: Option<&str>) -> Result<String> {
+        let mut part = Part::bytes(data).file_name("cover");
+        if let Some(mime) = mime {
+            part = part.mime_str(mime)?;
+        }
+
+        let form = Form::new().text("i", self.token.clone()).part("file", part);
+
+        let file: DriveFile = self
+            .client
+            .post(format!("{}/api/drive/files/create", self.instance))
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(file.id)
+    }
+
+    async fn post(&self, text: &str, visibility: Visibility, media_ids: &[String]) -> Result<()> {
+        let body = CreateNote {
+            i: &self.token,
+            text,
+            visibility: visibility_name(visibility),
+            // Misskey rejects an empty list of files.
+            file_ids: (!media_ids.is_empty()).then_some(media_ids),
+        };
+
+        // Misskey answers with the created note, which we don't need.
+        let _: serde::de::IgnoredAny = self.call("notes/create", &body).await?;
+        Ok(())
+    }
+
+    fn max_note(&self) -> usize {
+        1500
+    }
+
+    fn max_fields(&self) -> usize {
+        16
+    }
+
+    fn max_field_value(&self) -> usize {
+        1500
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_util::mock_server;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+
+    async fn update(Json(body): Json<Value>) -> (StatusCode, Json<Value>) {
+        if body["i"] != "token" {
+            let error = json!({ "error": { "code": "CREDENTIAL_REQUIRED" } });
+            return (StatusCode::UNAUTHORIZED, Json(error));
+        }
+
+        if body["fields"].as_array().is_some_and(|x| x.len() > 16) {
+            let error = json!({ "error": { "code": "INVALID_PARAM" } });
+            return (StatusCode::BAD_REQUEST, Json(error));
+        }
+
+        let me = json!({
+            "username": "user",
+            "host": null,
+            "description": body.get("description"),
+            "fields": body.get("fields").cloned().unwrap_or_else(|| json!([])),
+        });
+        (StatusCode::OK, Json(me))
+    }
+
+    async fn server() -> String {
+        let app = Router::new()
+            .route(
+                "/api/i",
+                post(|| async {
+                    Json(json!({ "username": "user", "host": "example.com", "description": null }))
+                }),
+            )
+            .route("/api/i/update", post(update));
+        mock_server(app).await
+    }
+
+    #[tokio::test]
+    async fn profile() {
+        let misskey = Misskey::new(&server().await, "token");
+        let profile = misskey.profile().await.unwrap();
+        assert_eq!(profile.acct, "user@example.com");
+        assert_eq!(profile.note, "");
+    }
+
+    #[tokio::test]
+    async fn set_note() {
+        let misskey = Misskey::new(&server().await, "token");
+        let profile = misskey.set_note("New bio").await.unwrap();
+        assert_eq!(profile.note, "New bio");
+    }
+
+    #[tokio::test]
+    async fn set_field() {
+        let misskey = Misskey::new(&server().await, "token");
+        let fields = [Field {
+            name: "Now playing".into(),
+            value: "Song by Artist".into(),
+        }];
+        let profile = misskey.set_fields(&fields).await.unwrap();
+        assert!(profile.fields == fields);
+    }
+
+    #[tokio::test]
+    async fn error_response() {
+        let misskey = Misskey::new(&server().await, "expired");
+        let err = misskey.set_note("New bio").await.err().unwrap();
+        let err = err.downcast::<reqwest::Error>().unwrap();
+        assert_eq!(err.status(), Some(StatusCode::UNAUTHORIZED));
+
+        let misskey = Misskey::new(&server().await, "token");
+        let field = Field {
+            name: "Field".into(),
+            value: "Value".into(),
+        };
+        let err = misskey.set_fields(&vec![field; 17]).await.err().unwrap();
+        let err = err.downcast::<reqwest::Error>().unwrap();
+        assert_eq!(err.status(), Some(StatusCode::BAD_REQUEST));
+    }
+}
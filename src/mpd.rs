@@ -1,15 +1,105 @@
 use anyhow::{anyhow, bail, Result};
 use bytes::BytesMut;
 use mpd_client::client::{CommandError, ConnectionEvent, ConnectionEvents, Subsystem};
-use mpd_client::commands::{QueueRange, SetBinaryLimit, SongId};
-pub use mpd_client::responses::{Song, Status};
+use mpd_client::commands::{
+    Next, Play, Previous, QueueRange, Seek, SetBinaryLimit, SetPause, SetVolume, Stop,
+};
+pub use mpd_client::commands::{SeekMode, SongId};
+pub use mpd_client::responses::{PlayState, Song, Status};
 use mpd_client::Client;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
 use tokio::net::TcpStream;
 
+/// What happened between the previous broadcast status and this one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    TrackChanged,
+    Seeked,
+    Paused,
+    Resumed,
+    Stopped,
+}
+
+impl Change {
+    pub const ALL: [Change; 5] = [
+        Change::TrackChanged,
+        Change::Seeked,
+        Change::Paused,
+        Change::Resumed,
+        Change::Stopped,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Change::TrackChanged => "track_changed",
+            Change::Seeked => "seeked",
+            Change::Paused => "paused",
+            Change::Resumed => "resumed",
+            Change::Stopped => "stopped",
+        }
+    }
+
+    /// Combines this change with a newer one, for sinks that skip intermediate statuses.
+    pub fn coalesce(self, newer: Change) -> Change {
+        match (self, newer) {
+            (_, Change::Stopped) => Change::Stopped,
+            (Change::TrackChanged, _) => Change::TrackChanged,
+            (_, newer) => newer,
+        }
+    }
+}
+
+/// Playback position as reported by MPD, along with when it was measured.
+#[derive(Clone, Copy, Debug)]
+pub struct Position {
+    pub elapsed: Duration,
+    pub measured_at: SystemTime,
+}
+
+impl Position {
+    /// Wall-clock time at which the song would have started, had it played without interruption.
+    pub fn started_at(&self) -> SystemTime {
+        self.measured_at - self.elapsed
+    }
+}
+
+/// What MPD reported at one point, before it's compared with what it reported previously.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub song: Option<Song>,
+    pub status: Status,
+    pub position: Option<Position>,
+}
+
+impl Snapshot {
+    pub fn song_id(&self) -> Option<SongId> {
+        self.status.current_song.map(|(_, id)| id)
+    }
+
+    pub fn with_change(self, change: Change) -> SongStatus {
+        SongStatus {
+            song: self.song,
+            status: self.status,
+            position: self.position,
+            change,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SongStatus {
     pub song: Option<Song>,
     pub status: Status,
+    pub position: Option<Position>,
+    pub change: Change,
+}
+
+impl SongStatus {
+    pub fn song_id(&self) -> Option<SongId> {
+        self.status.current_song.map(|(_, id)| id)
+    }
 }
 
 pub struct Mpd {
@@ -35,8 +125,12 @@ impl Mpd {
         Ok(self.client.command(mpd_client::commands::Status).await?)
     }
 
-    pub async fn song_status(&self) -> Result<SongStatus> {
+    pub async fn snapshot(&self) -> Result<Snapshot> {
         let status = self.status().await?;
+        let position = status.elapsed.map(|elapsed| Position {
+            elapsed,
+            measured_at: SystemTime::now(),
+        });
         let song = if let Some((_, id)) = status.current_song {
             let range = self.client.command(QueueRange::song(id)).await?;
             range.into_iter().next().map(|x| x.song)
@@ -44,7 +138,16 @@ impl Mpd {
             None
         };
 
-        Ok(SongStatus { song, status })
+        Ok(Snapshot {
+            song,
+            status,
+            position,
+        })
+    }
+
+    /// The current status, for sinks that need it before the next change is broadcast.
+    pub async fn song_status(&self, change: Change) -> Result<SongStatus> {
+        Ok(self.snapshot().await?.with_change(change))
     }
 
     pub async fn song_art(&self, id: SongId) -> Result<Option<(BytesMut, Option<String>)>> {
@@ -65,6 +168,92 @@ impl Mpd {
 
         Ok(self.client.album_art(&uri).await?)
     }
+
+    pub async fn play(&self) -> Result<()> {
+        Ok(self.client.command(Play::current()).await?)
+    }
+
+    pub async fn set_pause(&self, pause: bool) -> Result<()> {
+        Ok(self.client.command(SetPause(pause)).await?)
+    }
+
+    pub async fn toggle_pause(&self) -> Result<()> {
+        match self.status().await?.state {
+            PlayState::Playing => self.set_pause(true).await,
+            PlayState::Paused => self.set_pause(false).await,
+            PlayState::Stopped => self.play().await,
+        }
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        Ok(self.client.command(Stop).await?)
+    }
+
+    pub async fn next(&self) -> Result<()> {
+        Ok(self.client.command(Next).await?)
+    }
+
+    pub async fn previous(&self) -> Result<()> {
+        Ok(self.client.command(Previous).await?)
+    }
+
+    pub async fn seek(&self, mode: SeekMode) -> Result<()> {
+        Ok(self.client.command(Seek(mode)).await?)
+    }
+
+    pub async fn set_volume(&self, volume: u8) -> Result<()> {
+        Ok(self.client.command(SetVolume(volume)).await?)
+    }
+}
+
+/// Subsystems that can be configured to make the watcher re-read the status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleSubsystem {
+    Database,
+    Message,
+    Mixer,
+    Options,
+    Partition,
+    Player,
+    Queue,
+    StoredPlaylist,
+    Subscription,
+    Update,
+}
+
+impl IdleSubsystem {
+    pub fn matches(self, subsystem: &Subsystem) -> bool {
+        matches!(
+            (self, subsystem),
+            (Self::Database, Subsystem::Database)
+                | (Self::Message, Subsystem::Message)
+                | (Self::Mixer, Subsystem::Mixer)
+                | (Self::Options, Subsystem::Options)
+                | (Self::Partition, Subsystem::Partition)
+                | (Self::Player, Subsystem::Player)
+                | (Self::Queue, Subsystem::Queue)
+                | (Self::StoredPlaylist, Subsystem::StoredPlaylist)
+                | (Self::Subscription, Subsystem::Subscription)
+                | (Self::Update, Subsystem::Update)
+        )
+    }
+}
+
+pub fn subsystem_name(subsystem: &Subsystem) -> &'static str {
+    match subsystem {
+        Subsystem::Database => "database",
+        Subsystem::Message => "message",
+        Subsystem::Mixer => "mixer",
+        Subsystem::Options => "options",
+        Subsystem::Partition => "partition",
+        Subsystem::Player => "player",
+        Subsystem::Queue => "queue",
+        Subsystem::StoredPlaylist => "stored_playlist",
+        Subsystem::Subscription => "subscription",
+        Subsystem::Update => "update",
+        _ => "other",
+    }
 }
 
 pub async fn idle(events: &mut ConnectionEvents) -> Result<Subsystem> {
@@ -74,3 +263,79 @@ pub async fn idle(events: &mut ConnectionEvents) -> Result<Subsystem> {
         None => Err(anyhow!("connection closed")),
     }
 }
+
+/// Builds responses from text in MPD's wire format, as `mpd_client` doesn't let them be
+/// constructed directly.
+#[cfg(test)]
+pub mod test_util {
+    use super::{Position, Snapshot, Song, Status};
+    use mpd_client::commands::{self, Command as _};
+    use mpd_client::protocol::response::Frame;
+    use mpd_client::protocol::{Command, Connection};
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::time::{Duration, SystemTime};
+
+    /// Replies with one chunk per read, as the greeting has to arrive on its own.
+    struct Canned(VecDeque<Vec<u8>>);
+
+    impl Read for Canned {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(chunk) => (&chunk[..]).read(buf),
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl Write for Canned {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(command: &str, fields: &str) -> Frame {
+        let chunks = vec![
+            b"OK MPD 0.23.5\n".to_vec(),
+            format!("{}OK\n", fields).into_bytes(),
+        ];
+        let mut connection = Connection::connect(Canned(chunks.into())).unwrap();
+        let response = connection.command(Command::new(command)).unwrap();
+        response.into_single_frame().unwrap()
+    }
+
+    pub fn status(fields: &str) -> Status {
+        let fields = format!("repeat: 0\nrandom: 0\nconsume: 0\n{}", fields);
+        commands::Status.response(frame("status", &fields)).unwrap()
+    }
+
+    pub fn song(fields: &str) -> Song {
+        let response = commands::CurrentSong.response(frame("currentsong", fields));
+        response.unwrap().unwrap().song
+    }
+
+    /// A song with the given ID and title, playing at `elapsed` seconds as of `measured_at`.
+    pub fn playing(id: u64, title: &str, elapsed: f64, measured_at: SystemTime) -> Snapshot {
+        let song = song(&format!(
+            "file: {}.flac\nTitle: {}\nduration: 180.000\nPos: 0\nId: {}\n",
+            id, title, id
+        ));
+        let status = status(&format!(
+            "state: play\nsong: 0\nsongid: {}\nelapsed: {:.3}\nduration: 180.000\n",
+            id, elapsed
+        ));
+
+        Snapshot {
+            song: Some(song),
+            status,
+            position: Some(Position {
+                elapsed: Duration::from_secs_f64(elapsed),
+                measured_at,
+            }),
+        }
+    }
+}
@@ -1,18 +1,171 @@
-use super::mpd::Mpd;
+use super::config::Config;
+use super::mpd::{subsystem_name, Change, Mpd, PlayState, Snapshot};
 use super::{mpd, StatusTx};
 use anyhow::Result;
 use log::*;
 use mpd_client::client::ConnectionEvents;
+use std::time::Duration;
+
+/// How far the playback position may stray from the wall clock before it counts as a seek.
+const SEEK_TOLERANCE: Duration = Duration::from_secs(2);
+
+fn detect_seek(previous: &Snapshot, current: &Snapshot) -> Option<Change> {
+    let (previous_position, current_position) = match (previous.position, current.position) {
+        (Some(previous), Some(current)) => (previous, current),
+        _ => return None,
+    };
+
+    // While paused the position shouldn't move at all, while playing it should move along with
+    // the wall clock.
+    let expected = if current.status.state == PlayState::Playing {
+        current_position
+            .measured_at
+            .duration_since(previous_position.started_at())
+            .unwrap_or_default()
+    } else {
+        previous_position.elapsed
+    };
+
+    let drift = expected.abs_diff(current_position.elapsed);
+    if drift <= SEEK_TOLERANCE {
+        return None;
+    }
+
+    // The same song starting over after having played to the end is a repeat, not a seek.
+    let finished = current
+        .status
+        .duration
+        .is_some_and(|duration| expected + SEEK_TOLERANCE >= duration);
+
+    if finished && current_position.elapsed <= SEEK_TOLERANCE {
+        Some(Change::TrackChanged)
+    } else {
+        debug!("position drifted by {:?}", drift);
+        Some(Change::Seeked)
+    }
+}
+
+fn detect_change(previous: Option<&Snapshot>, current: &Snapshot) -> Option<Change> {
+    let state = current.status.state;
+
+    let previous = match previous {
+        Some(previous) => previous,
+        None if state == PlayState::Stopped => return Some(Change::Stopped),
+        None => return Some(Change::TrackChanged),
+    };
+
+    match (previous.status.state, state) {
+        (PlayState::Stopped, PlayState::Stopped) => None,
+        (_, PlayState::Stopped) => Some(Change::Stopped),
+        (PlayState::Stopped, _) => Some(Change::TrackChanged),
+        _ if previous.song_id() != current.song_id() => Some(Change::TrackChanged),
+        // Streams keep the same queue entry, but update its tags with each new title.
+        _ if previous.song != current.song => Some(Change::TrackChanged),
+        (PlayState::Playing, PlayState::Paused) => Some(Change::Paused),
+        (PlayState::Paused, PlayState::Playing) => Some(Change::Resumed),
+        _ => detect_seek(previous, current),
+    }
+}
+
+async fn idle(events: &mut ConnectionEvents, config: &Config) -> Result<()> {
+    loop {
+        let subsystem = mpd::idle(events).await?;
+
+        if config.idle_subsystems.iter().any(|x| x.matches(&subsystem)) {
+            break Ok(());
+        }
+
+        trace!("ignoring {} event", subsystem_name(&subsystem));
+    }
+}
+
+pub async fn mpd_watcher(
+    mpd: &Mpd,
+    mut events: ConnectionEvents,
+    tx: StatusTx,
+    config: &Config,
+) -> Result<!> {
+    let mut previous: Option<Snapshot> = None;
 
-pub async fn mpd_watcher(mpd: &Mpd, mut events: ConnectionEvents, tx: StatusTx) -> Result<!> {
     loop {
         trace!("getting status");
-        let song_status = mpd.song_status().await?;
+        let snapshot = mpd.snapshot().await?;
+
+        if let Some(change) = detect_change(previous.as_ref(), &snapshot) {
+            trace!("sending status");
+            tx.send(snapshot.clone().with_change(change))?;
+            info!("sent status ({:?})", change);
+        } else {
+            debug!("no meaningful change");
+        }
+
+        previous = Some(snapshot);
+
+        trace!("idling");
+        idle(&mut events, config).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd::test_util::playing;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn stream_title_change_is_a_track_change() {
+        let now = SystemTime::now();
+        let previous = playing(1, "First", 10.0, now);
+        let current = playing(1, "Second", 15.0, now + Duration::from_secs(5));
+
+        assert_eq!(
+            detect_change(Some(&previous), &current),
+            Some(Change::TrackChanged)
+        );
+    }
+
+    #[test]
+    fn unchanged_song_is_ignored() {
+        let now = SystemTime::now();
+        let previous = playing(1, "First", 10.0, now);
+        let current = playing(1, "First", 15.0, now + Duration::from_secs(5));
+
+        assert_eq!(detect_change(Some(&previous), &current), None);
+    }
+
+    #[test]
+    fn drift_at_tolerance_is_not_a_seek() {
+        let now = SystemTime::now();
+        let previous = playing(1, "First", 10.0, now);
+        let current = playing(1, "First", 17.0, now + Duration::from_secs(5));
+
+        assert_eq!(detect_seek(&previous, &current), None);
+    }
+
+    #[test]
+    fn drift_past_tolerance_is_a_seek() {
+        let now = SystemTime::now();
+        let previous = playing(1, "First", 10.0, now);
+        let current = playing(1, "First", 17.5, now + Duration::from_secs(5));
+
+        assert_eq!(detect_seek(&previous, &current), Some(Change::Seeked));
+    }
+
+    #[test]
+    fn seeking_backwards_is_a_seek() {
+        let now = SystemTime::now();
+        let previous = playing(1, "First", 10.0, now);
+        let current = playing(1, "First", 2.5, now + Duration::from_secs(5));
+
+        assert_eq!(detect_seek(&previous, &current), Some(Change::Seeked));
+    }
 
-        trace!("sending status");
-        tx.send(song_status)?;
+    #[test]
+    fn restarting_a_finished_song_is_a_track_change() {
+        let now = SystemTime::now();
+        let previous = playing(1, "First", 170.0, now);
+        let current = playing(1, "First", 1.0, now + Duration::from_secs(11));
 
-        info!("sent status, idling");
-        mpd::idle(&mut events).await?;
+        assert_eq!(detect_seek(&previous, &current), Some(Change::TrackChanged));
     }
 }
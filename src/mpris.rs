@@ -0,0 +1,300 @@
+use crate::mpd::{Mpd, PlayState, SeekMode, SongStatus};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use zbus::fdo;
+use zbus::interface;
+use zbus::zvariant::{ObjectPath, Value};
+use zbus::SignalContext;
+
+pub const PATH: &str = "/org/mpris/MediaPlayer2";
+pub const BUS_NAME: &str = "org.mpris.MediaPlayer2.mpdiscord";
+
+fn mpd_error(err: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(err.to_string())
+}
+
+fn micros(duration: Duration) -> i64 {
+    duration.as_micros() as i64
+}
+
+/// The `org.mpris.MediaPlayer2` interface, which mostly says what we can't do.
+pub struct Root;
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "MPD"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec![]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// The `org.mpris.MediaPlayer2.Player` interface, reflecting the last broadcast status and
+/// forwarding controls to MPD.
+pub struct Player {
+    pub mpd: Arc<Mpd>,
+    pub song_status: Option<SongStatus>,
+    pub art_url: Option<String>,
+}
+
+impl Player {
+    fn track_id(&self) -> Option<ObjectPath<'static>> {
+        let song_id = self.song_status.as_ref()?.song_id()?;
+        ObjectPath::try_from(format!("{}/Track/{}", PATH, song_id.0)).ok()
+    }
+
+    fn state(&self) -> PlayState {
+        self.song_status
+            .as_ref()
+            .map_or(PlayState::Stopped, |x| x.status.state)
+    }
+
+    /// Where playback is now, extrapolating from the last measurement while playing.
+    pub fn current_position(&self) -> Duration {
+        let position = match self.song_status.as_ref().and_then(|x| x.position) {
+            Some(position) => position,
+            None => return Duration::ZERO,
+        };
+
+        if self.state() == PlayState::Playing {
+            let since_measured = SystemTime::now()
+                .duration_since(position.measured_at)
+                .unwrap_or_default();
+            position.elapsed + since_measured
+        } else {
+            position.elapsed
+        }
+    }
+
+    pub fn position_micros(&self) -> i64 {
+        micros(self.current_position())
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    async fn next(&self) -> fdo::Result<()> {
+        self.mpd.next().await.map_err(mpd_error)
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        self.mpd.previous().await.map_err(mpd_error)
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.mpd.set_pause(true).await.map_err(mpd_error)
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        self.mpd.toggle_pause().await.map_err(mpd_error)
+    }
+
+    async fn stop(&self) -> fdo::Result<()> {
+        self.mpd.stop().await.map_err(mpd_error)
+    }
+
+    async fn play(&self) -> fdo::Result<()> {
+        match self.state() {
+            PlayState::Paused => self.mpd.set_pause(false).await,
+            _ => self.mpd.play().await,
+        }
+        .map_err(mpd_error)
+    }
+
+    /// Seeks relative to the current position, in microseconds.
+    async fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let amount = Duration::from_micros(offset.unsigned_abs());
+        let mode = if offset >= 0 {
+            SeekMode::Forward(amount)
+        } else if amount >= self.current_position() {
+            SeekMode::Absolute(Duration::ZERO)
+        } else {
+            SeekMode::Backward(amount)
+        };
+
+        self.mpd.seek(mode).await.map_err(mpd_error)
+    }
+
+    async fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        if self.track_id().as_ref() != Some(&track_id) || position < 0 {
+            return Ok(());
+        }
+
+        let position = Duration::from_micros(position as u64);
+        let duration = self.song_status.as_ref().and_then(|x| x.status.duration);
+        if duration.is_some_and(|x| position > x) {
+            return Ok(());
+        }
+
+        self.mpd
+            .seek(SeekMode::Absolute(position))
+            .await
+            .map_err(mpd_error)
+    }
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported("can't open URIs".to_string()))
+    }
+
+    #[zbus(signal)]
+    pub async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        match self.state() {
+            PlayState::Playing => "Playing",
+            PlayState::Paused => "Paused",
+            PlayState::Stopped => "Stopped",
+        }
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<&str, Value<'_>> {
+        let mut metadata = HashMap::new();
+
+        let song_status = match &self.song_status {
+            Some(song_status) => song_status,
+            None => return metadata,
+        };
+        let song = match &song_status.song {
+            Some(song) => song,
+            None => return metadata,
+        };
+
+        if let Some(track_id) = self.track_id() {
+            metadata.insert("mpris:trackid", Value::from(track_id));
+        }
+
+        if let Some(duration) = song_status.status.duration {
+            metadata.insert("mpris:length", Value::from(micros(duration)));
+        }
+
+        if let Some(art_url) = &self.art_url {
+            metadata.insert("mpris:artUrl", Value::from(art_url.as_str()));
+        }
+
+        if let Some(title) = song.title() {
+            metadata.insert("xesam:title", Value::from(title));
+        }
+
+        if let Some(album) = song.album() {
+            metadata.insert("xesam:album", Value::from(album));
+        }
+
+        let artists = song.artists();
+        if !artists.is_empty() {
+            metadata.insert("xesam:artist", Value::from(artists.to_vec()));
+        }
+
+        let album_artists = song.album_artists();
+        if !album_artists.is_empty() {
+            metadata.insert("xesam:albumArtist", Value::from(album_artists.to_vec()));
+        }
+
+        metadata.insert("xesam:url", Value::from(song.url.as_str()));
+
+        metadata
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        self.position_micros()
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    /// MPD's volume as of the last broadcast status, as mixer changes on their own aren't.
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.song_status
+            .as_ref()
+            .map_or(0.0, |x| f64::from(x.status.volume) / 100.0)
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        let volume = (volume.clamp(0.0, 1.0) * 100.0).round() as u8;
+        self.mpd.set_volume(volume).await.map_err(mpd_error)?;
+
+        if let Some(song_status) = &mut self.song_status {
+            song_status.status.volume = volume;
+        }
+
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        self.song_status
+            .as_ref()
+            .is_some_and(|x| x.status.duration.is_some())
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,61 @@
+use super::bluesky::Bluesky;
+use super::config::{Backend, MastodonConfig, Visibility};
+use super::mastodon::{Flavor, Mastodon};
+use super::misskey::Misskey;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub value: String,
+}
+
+/// The user-editable parts of an account, as the backend stores them.
+#[derive(Clone)]
+pub struct Profile {
+    pub acct: String,
+    pub note: String,
+    pub fields: Vec<Field>,
+}
+
+#[async_trait::async_trait]
+pub trait ProfileBackend: Send + Sync {
+    async fn profile(&self) -> Result<Profile>;
+
+    /// Replaces the note, leaving the fields untouched.
+    async fn set_note(&self, note: &str) -> Result<Profile>;
+
+    /// Replaces all profile metadata fields, leaving the note untouched.
+    async fn set_fields(&self, fields: &[Field]) -> Result<Profile>;
+
+    /// Uploads an attachment, returning an ID that can be passed to `post`.
+    async fn upload_media(&self, data: Vec<u8>, mime: Option<&str>) -> Result<String>;
+
+    async fn post(&self, text: &str, visibility: Visibility, media_ids: &[String]) -> Result<()>;
+
+    /// Longest note, in characters.
+    fn max_note(&self) -> usize;
+
+    fn max_fields(&self) -> usize;
+
+    fn max_field_value(&self) -> usize;
+}
+
+pub fn backend(config: &MastodonConfig) -> Box<dyn ProfileBackend> {
+    let instance = config.instance.trim_end_matches('/');
+    let token = &config.token;
+
+    match config.backend {
+        Backend::Mastodon => Box::new(Mastodon::new(instance, token, Flavor::Mastodon)),
+        Backend::Pleroma | Backend::Akkoma => {
+            Box::new(Mastodon::new(instance, token, Flavor::Pleroma))
+        }
+        Backend::GoToSocial => Box::new(Mastodon::new(instance, token, Flavor::GoToSocial)),
+        Backend::Misskey | Backend::Sharkey => Box::new(Misskey::new(instance, token)),
+        Backend::Bluesky => {
+            let username = config.username.as_deref().unwrap_or_default();
+            Box::new(Bluesky::new(instance, username, token))
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use super::util::write_atomic;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+
+/// A queue that is written to disk after every change, so that nothing is lost if submitting
+/// fails and mpdiscord is restarted before it can be retried.
+pub struct DurableQueue<T> {
+    path: PathBuf,
+    items: Vec<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> DurableQueue<T> {
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+
+        let items = match fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self { path, items })
+    }
+
+    /// Opens the queue that items rejected for good are moved to, kept next to this one.
+    pub async fn open_rejected(&self) -> Result<Self> {
+        Self::open(self.path.with_extension("rejected.json")).await
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub async fn push(&mut self, item: T) -> Result<()> {
+        self.items.push(item);
+        self.save().await
+    }
+
+    pub async fn remove_front(&mut self, count: usize) -> Result<()> {
+        self.items.drain(..count.min(self.items.len()));
+        self.save().await
+    }
+
+    pub async fn retain(&mut self, keep: impl FnMut(&T) -> bool) -> Result<()> {
+        self.items.retain(keep);
+        self.save().await
+    }
+
+    async fn save(&self) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_vec(&self.items)?).await
+    }
+}
+
+/// Exponential backoff between attempts to submit a queue.
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    const MIN: Duration = Duration::from_secs(30);
+    const MAX: Duration = Duration::from_secs(60 * 60);
+
+    /// Starts over after an attempt succeeded.
+    pub fn reset(&mut self) {
+        self.next = Self::MIN;
+    }
+
+    /// How long to wait after a failed attempt, doubling the wait for the one after.
+    pub fn failed(&mut self) -> Duration {
+        let next = self.next;
+        self.next = (next * 2).min(Self::MAX);
+        next
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: Self::MIN }
+    }
+}
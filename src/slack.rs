@@ -0,0 +1,194 @@
+use super::config::SlackConfig;
+use anyhow::{bail, Result};
+use log::*;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Longest status text Slack accepts, in characters.
+pub const MAX_STATUS_TEXT: usize = 100;
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+#[derive(Serialize)]
+struct Status<'a> {
+    status_text: &'a str,
+    status_emoji: &'a str,
+    /// Unix time at which Slack clears the status, 0 for never.
+    status_expiration: u64,
+}
+
+#[derive(Serialize)]
+struct SetProfile<'a> {
+    profile: Status<'a>,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    error: Option<String>,
+}
+
+pub struct Slack {
+    client: Client,
+    api_base: String,
+    token: String,
+}
+
+impl Slack {
+    pub fn new(config: &SlackConfig) -> Self {
+        Self {
+            client: Client::new(),
+            api_base: config.api_base.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+        }
+    }
+
+    pub async fn set_status(&self, text: &str, emoji: &str, expiration: u64) -> Result<()> {
+        let body = SetProfile {
+            profile: Status {
+                status_text: text,
+                status_emoji: emoji,
+                status_expiration: expiration,
+            },
+        };
+        let mut retries = 0;
+
+        loop {
+            let response = self
+                .client
+                .post(format!("{}/users.profile.set", self.api_base))
+                .bearer_auth(&self.token)
+                .json(&body)
+                .send()
+                .await?;
+
+            if response.status() == StatusCode::TOO_MANY_REQUESTS
+                && retries < MAX_RATE_LIMIT_RETRIES
+            {
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|x| x.to_str().ok()?.parse().ok())
+                    .unwrap_or(30);
+                debug!("rate limited, retrying in {}s", retry_after);
+                sleep(Duration::from_secs(retry_after)).await;
+                retries += 1;
+                continue;
+            }
+
+            // Slack reports most errors with a 200 and `ok` set to false.
+            let response: ApiResponse = response.error_for_status()?.json().await?;
+            if !response.ok {
+                bail!(
+                    "users.profile.set failed: {}",
+                    response.error.as_deref().unwrap_or("unknown error")
+                );
+            }
+
+            break Ok(());
+        }
+    }
+
+    pub async fn clear_status(&self) -> Result<()> {
+        self.set_status("", "", 0).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::util::test_util::mock_server;
+    use axum::http::HeaderMap;
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    type Requests = Arc<Mutex<Vec<Value>>>;
+
+    /// A Slack API that records every profile update and answers them with `responses` in turn,
+    /// then with success.
+    async fn slack(responses: Vec<(StatusCode, Value)>) -> (Slack, Requests) {
+        let requests = Requests::default();
+        let responses = Arc::new(Mutex::new(responses));
+        let handler = {
+            let requests = requests.clone();
+            move |headers: HeaderMap, Json(body): Json<Value>| async move {
+                assert_eq!(headers["authorization"], "Bearer token");
+                requests.lock().unwrap().push(body);
+
+                let mut responses = responses.lock().unwrap();
+                if responses.is_empty() {
+                    return Json(json!({ "ok": true })).into_response();
+                }
+                let (status, body) = responses.remove(0);
+                if status == StatusCode::TOO_MANY_REQUESTS {
+                    (status, [(RETRY_AFTER, "1")]).into_response()
+                } else {
+                    (status, Json(body)).into_response()
+                }
+            }
+        };
+        let app = Router::new().route("/users.profile.set", post(handler));
+
+        let config = test_util::config(&format!(
+            "[slack]\ntoken = \"token\"\napi_base = \"{}\"\n",
+            mock_server(app).await
+        ));
+        (Slack::new(config.slack.as_ref().unwrap()), requests)
+    }
+
+    #[tokio::test]
+    async fn sends_emoji_and_expiration() {
+        let (slack, requests) = slack(vec![]).await;
+        slack
+            .set_status("Title - Artist", ":notes:", 1_700_000_000)
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests[..],
+            [json!({ "profile": {
+                "status_text": "Title - Artist",
+                "status_emoji": ":notes:",
+                "status_expiration": 1_700_000_000,
+            }})]
+        );
+    }
+
+    #[tokio::test]
+    async fn clearing_empties_the_status() {
+        let (slack, requests) = slack(vec![]).await;
+        slack.clear_status().await.unwrap();
+
+        let profile = &requests.lock().unwrap()[0]["profile"];
+        assert_eq!(profile["status_text"], "");
+        assert_eq!(profile["status_emoji"], "");
+        assert_eq!(profile["status_expiration"], 0);
+    }
+
+    #[tokio::test]
+    async fn errors_in_body_fail() {
+        let error = json!({ "ok": false, "error": "invalid_auth" });
+        let (slack, _) = slack(vec![(StatusCode::OK, error)]).await;
+
+        let err = slack.set_status("Title", ":notes:", 0).await.unwrap_err();
+        assert!(err.to_string().contains("invalid_auth"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn waits_out_rate_limits() {
+        let (slack, requests) = slack(vec![(StatusCode::TOO_MANY_REQUESTS, json!({}))]).await;
+
+        let start = Instant::now();
+        slack.set_status("Title", ":notes:", 0).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+pub type Vars = HashMap<String, String>;
+
+/// Replaces `{name}` placeholders with the matching variable, or nothing if it isn't set.
+/// Literal braces are written as `{{` and `}}`.
+pub fn render(template: &str, vars: &Vars) -> String {
+    render_with(template, vars, str::to_string)
+}
+
+/// Like `render`, but passes every substituted value through `escape` first.
+pub fn render_with(template: &str, vars: &Vars, escape: impl Fn(&str) -> String) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        output.push_str(&rest[..start]);
+        let brace = &rest[start..];
+
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            output.push_str(&brace[..1]);
+            rest = &brace[2..];
+            continue;
+        }
+
+        match brace.find('}') {
+            Some(end) if brace.starts_with('{') => {
+                if let Some(value) = vars.get(&brace[1..end]) {
+                    output.push_str(&escape(value));
+                }
+                rest = &brace[end + 1..];
+            }
+            _ => {
+                output.push_str(&brace[..1]);
+                rest = &brace[1..];
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        let mut vars = Vars::new();
+        vars.insert("title".into(), "Song".into());
+        vars.insert("artist".into(), "\"Quoted\"".into());
+        vars
+    }
+
+    #[test]
+    fn substitutes_variables() {
+        assert_eq!(render("{title} by {artist}", &vars()), "Song by \"Quoted\"");
+    }
+
+    #[test]
+    fn missing_variables_are_empty() {
+        assert_eq!(render("{title} from {album}!", &vars()), "Song from !");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{title}} {{{title}}}", &vars()), "{title} {Song}");
+    }
+
+    #[test]
+    fn unmatched_braces_are_kept() {
+        assert_eq!(render("} {title", &vars()), "} {title");
+    }
+
+    #[test]
+    fn escapes_values_only() {
+        let rendered = render_with("\"{artist}\"", &vars(), |x| x.replace('"', "\\\""));
+        assert_eq!(rendered, "\"\\\"Quoted\\\"\"");
+    }
+}
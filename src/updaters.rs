@@ -1,11 +1,35 @@
+use super::config::RateLimit;
+use super::listen::{ListenEvent, ListenTracker};
 use super::mpd::SongStatus;
+use super::queue::{Backoff, DurableQueue};
 use super::StatusRx;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use log::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::path::Path;
 use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep_until, Instant};
 
+pub mod activitypub;
 pub mod discord;
+pub mod exec;
+pub mod file;
+pub mod github;
+pub mod history;
+pub mod icecast;
+pub mod irc;
+pub mod lastfm;
+pub mod listenbrainz;
 pub mod mastodon;
+pub mod matrix;
+pub mod mpris;
+pub mod mqtt;
+pub mod notifications;
+pub mod slack;
+pub mod webhook;
+pub mod xmpp;
 
 async fn safe_recv(rx: &mut StatusRx) -> Result<SongStatus> {
     loop {
@@ -19,3 +43,249 @@ async fn safe_recv(rx: &mut StatusRx) -> Result<SongStatus> {
         }
     }
 }
+
+struct Throttle {
+    limit: RateLimit,
+    last_sent: Option<Instant>,
+    pending: Option<(SongStatus, Instant)>,
+}
+
+impl Throttle {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            last_sent: None,
+            pending: None,
+        }
+    }
+
+    fn push(&mut self, mut song_status: SongStatus) {
+        if let Some((pending, _)) = self.pending.take() {
+            trace!("superseded pending status");
+            song_status.change = pending.change.coalesce(song_status.change);
+        }
+
+        self.pending = Some((song_status, Instant::now() + self.limit.debounce()));
+    }
+
+    /// Waits for a status, then keeps replacing it with newer ones until the debounce window
+    /// and the minimum interval since the last update have both passed.
+    ///
+    /// This is cancel safe, a pending status is kept for the next call.
+    async fn recv(&mut self, rx: &mut StatusRx) -> Result<SongStatus> {
+        loop {
+            let deadline = match (&self.pending, self.last_sent) {
+                (None, _) => {
+                    let song_status = safe_recv(rx).await?;
+                    self.push(song_status);
+                    continue;
+                }
+                (Some((_, debounce_end)), Some(last_sent)) => {
+                    (*debounce_end).max(last_sent + self.limit.min_interval())
+                }
+                (Some((_, debounce_end)), None) => *debounce_end,
+            };
+
+            tokio::select! {
+                _ = sleep_until(deadline) => break,
+                next = safe_recv(rx) => self.push(next?),
+            }
+        }
+
+        self.last_sent = Some(Instant::now());
+
+        let (song_status, _) = self.pending.take().unwrap();
+        Ok(song_status)
+    }
+}
+
+/// Submits queued items in batches, returning whether the queue was emptied. Items that are
+/// rejected are moved to `rejected`, so they don't hold up the rest of the queue.
+async fn flush<T, S, F>(
+    name: &str,
+    queue: &mut DurableQueue<T>,
+    rejected: &mut DurableQueue<T>,
+    max_batch: usize,
+    submit: &S,
+    is_rejected: &impl Fn(&Error) -> bool,
+) -> Result<bool>
+where
+    T: Clone + Serialize + DeserializeOwned,
+    S: Fn(Vec<T>) -> F,
+    F: Future<Output = Result<()>>,
+{
+    // After a batch is rejected, items are sent one at a time to find the ones at fault.
+    let mut batch_size = max_batch;
+
+    while !queue.is_empty() {
+        let count = queue.items().len().min(batch_size);
+
+        match submit(queue.items()[..count].to_vec()).await {
+            Ok(()) => info!("submitted {} {}", count, name),
+            Err(err) if is_rejected(&err) && count > 1 => {
+                warn!(
+                    "batch of {} was rejected, retrying one at a time: {:#}",
+                    name, err
+                );
+                batch_size = 1;
+                continue;
+            }
+            Err(err) if is_rejected(&err) => {
+                warn!(
+                    "one of the {} was rejected, moving it to {}: {:#}",
+                    name,
+                    rejected.path().display(),
+                    err
+                );
+                rejected.push(queue.items()[0].clone()).await?;
+            }
+            Err(err) => {
+                warn!("couldn't submit {}, will retry: {:#}", name, err);
+                return Ok(false);
+            }
+        }
+
+        queue.remove_front(count).await?;
+    }
+
+    Ok(true)
+}
+
+/// Runs a sink that keeps what was listened to in a queue on disk and submits it in batches,
+/// backing off while submitting fails. `on_event` reacts to each listen event, returning what to
+/// queue for it, and `is_rejected` tells errors caused by the items themselves apart.
+async fn queued_updater<T, E, EF, S, SF>(
+    name: &str,
+    queue_path: &Path,
+    max_batch: usize,
+    mut rx: StatusRx,
+    mut on_event: E,
+    submit: S,
+    is_rejected: impl Fn(&Error) -> bool,
+) -> Result<!>
+where
+    T: Clone + Serialize + DeserializeOwned,
+    E: FnMut(ListenEvent) -> EF,
+    EF: Future<Output = Option<T>>,
+    S: Fn(Vec<T>) -> SF,
+    SF: Future<Output = Result<()>>,
+{
+    let mut queue = DurableQueue::open(queue_path).await?;
+    let mut rejected = queue.open_rejected().await?;
+    let mut tracker = ListenTracker::default();
+
+    let mut backoff = Backoff::default();
+    let mut retry_at = (!queue.is_empty()).then(Instant::now);
+
+    loop {
+        let deadline = tracker.deadline();
+
+        let events = tokio::select! {
+            song_status = safe_recv(&mut rx) => tracker.update(&song_status?),
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                tracker.poll().into_iter().collect()
+            }
+            _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => {
+                trace!("retrying queued {}", name);
+                retry_at = None;
+                vec![]
+            }
+        };
+
+        for event in events {
+            if let Some(item) = on_event(event).await {
+                queue.push(item).await?;
+            }
+        }
+
+        // While backing off, new items wait in the queue for the next retry.
+        if retry_at.is_none() && !queue.is_empty() {
+            if flush(
+                name,
+                &mut queue,
+                &mut rejected,
+                max_batch,
+                &submit,
+                &is_rejected,
+            )
+            .await?
+            {
+                backoff.reset();
+            } else {
+                retry_at = Some(Instant::now() + backoff.failed());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd::test_util::playing;
+    use crate::mpd::Change;
+    use std::time::{Duration, SystemTime};
+    use tokio::sync::broadcast;
+    use tokio::time::sleep;
+
+    fn limit(debounce_ms: u64, min_interval_ms: u64) -> RateLimit {
+        RateLimit {
+            debounce_ms,
+            min_interval_ms,
+        }
+    }
+
+    fn status(id: u64, change: Change) -> SongStatus {
+        playing(id, "Song", 0.0, SystemTime::now()).with_change(change)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_waits_for_quiet() {
+        let (tx, mut rx) = broadcast::channel(16);
+        let mut throttle = Throttle::new(limit(1000, 0));
+        let start = Instant::now();
+
+        tokio::spawn(async move {
+            tx.send(status(1, Change::TrackChanged)).unwrap();
+            sleep(Duration::from_millis(600)).await;
+            tx.send(status(2, Change::TrackChanged)).unwrap();
+            sleep(Duration::from_secs(60)).await;
+        });
+
+        let song_status = throttle.recv(&mut rx).await.unwrap();
+        assert_eq!(song_status.song_id().unwrap().0, 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(1600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn min_interval_delays_next_update() {
+        let (tx, mut rx) = broadcast::channel(16);
+        let mut throttle = Throttle::new(limit(100, 5000));
+        let start = Instant::now();
+
+        tx.send(status(1, Change::TrackChanged)).unwrap();
+        throttle.recv(&mut rx).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+        tx.send(status(2, Change::TrackChanged)).unwrap();
+        let song_status = throttle.recv(&mut rx).await.unwrap();
+        assert_eq!(song_status.song_id().unwrap().0, 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(5100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn superseded_changes_coalesce() {
+        let (tx, mut rx) = broadcast::channel(16);
+        let mut throttle = Throttle::new(limit(1000, 0));
+
+        tx.send(status(1, Change::TrackChanged)).unwrap();
+        tx.send(status(1, Change::Seeked)).unwrap();
+        tx.send(status(1, Change::Paused)).unwrap();
+        let song_status = throttle.recv(&mut rx).await.unwrap();
+        assert_eq!(song_status.change, Change::TrackChanged);
+
+        tx.send(status(1, Change::Resumed)).unwrap();
+        tx.send(status(1, Change::Stopped)).unwrap();
+        let song_status = throttle.recv(&mut rx).await.unwrap();
+        assert_eq!(song_status.change, Change::Stopped);
+    }
+}
@@ -0,0 +1,65 @@
+use super::safe_recv;
+use crate::activitypub::ActivityPub;
+use crate::config::{ActivityPubConfig, Config};
+use crate::mpd::Change;
+use crate::queue::Backoff;
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+use tokio::time::{sleep_until, Instant};
+
+pub async fn activitypub_updater(
+    activitypub: &ActivityPub,
+    activitypub_config: &ActivityPubConfig,
+    config: &Config,
+    mut rx: StatusRx,
+) -> Result<!> {
+    for id in &activitypub_config.follow {
+        if activitypub.is_following(id).await {
+            continue;
+        }
+
+        match activitypub.follow(id).await {
+            Ok(()) => info!("sent follow request to {}", id),
+            Err(err) => warn!("couldn't follow {}: {}", id, err),
+        }
+    }
+
+    let mut backoff = Backoff::default();
+    let mut retry_at = activitypub
+        .has_pending_deliveries()
+        .await
+        .then(Instant::now);
+
+    loop {
+        tokio::select! {
+            song_status = safe_recv(&mut rx) => {
+                let song_status = song_status?;
+
+                if song_status.change != Change::TrackChanged {
+                    continue;
+                }
+
+                if let Some(listen) = activitypub.listen(&song_status, config) {
+                    debug!("publishing listen: {}", listen["object"]["name"]);
+                    activitypub.publish(listen).await?;
+                    info!("published listen");
+                } else {
+                    debug!("(no song)");
+                }
+            }
+            _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => {
+                trace!("retrying deliveries");
+                retry_at = None;
+                if activitypub.retry_deliveries().await? {
+                    backoff.reset();
+                }
+            }
+        }
+
+        // While backing off, failed deliveries wait in the queue for the next retry.
+        if retry_at.is_none() && activitypub.has_pending_deliveries().await {
+            retry_at = Some(Instant::now() + backoff.failed());
+        }
+    }
+}
@@ -1,20 +1,32 @@
-use super::safe_recv;
+use super::Throttle;
 use crate::config::Config;
-use crate::conversions::get_activity;
+use crate::conversions::{get_activity, get_timestamps};
 use crate::discord::DiscordHandle;
+use crate::mpd::Change;
 use crate::StatusRx;
 use anyhow::Result;
+use discord_sdk::activity::Activity;
 use log::*;
 use std::sync::Arc;
 
 pub async fn discord_updater(config: Arc<Config>, mut rx: StatusRx) -> Result<!> {
     let mut handle = DiscordHandle::new(config.discord_client_id);
+    let mut throttle = Throttle::new(config.rate_limits.discord);
+    let mut last_activity: Option<Activity> = None;
 
     loop {
         trace!("getting status");
-        let song_status = safe_recv(&mut rx).await?;
+        let song_status = throttle.recv(&mut rx).await?;
 
-        let activity = get_activity(&song_status, &config)?;
+        let activity = match (song_status.change, last_activity.take()) {
+            (Change::Seeked | Change::Paused | Change::Resumed, Some(mut activity)) => {
+                debug!("correcting timestamps");
+                activity.timestamps = get_timestamps(&song_status)?;
+                activity
+            }
+            _ => get_activity(&song_status, &config)?,
+        };
+        last_activity = Some(activity.clone());
 
         trace!("updating activity");
         handle.update_activity(activity).await?;
@@ -0,0 +1,194 @@
+use super::safe_recv;
+use crate::config::{Config, ExecConfig};
+use crate::conversions::get_vars;
+use crate::mpd::{Change, Mpd, SongId};
+use crate::template::Vars;
+use crate::util::write_atomic;
+use crate::StatusRx;
+use anyhow::Result;
+use bytes::BytesMut;
+use log::*;
+use std::future::Future;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+/// Runs a hook with every template variable in its environment as `MPDISCORD_<NAME>`, and the
+/// saved cover as `MPDISCORD_ART`.
+async fn run_hook(command: &str, vars: &Vars, art: Option<&Path>, limit: Duration) -> Result<()> {
+    let mut process = Command::new("sh");
+    process
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    for (name, value) in vars {
+        process.env(format!("MPDISCORD_{}", name.to_uppercase()), value);
+    }
+
+    if let Some(art) = art {
+        process.env("MPDISCORD_ART", art);
+    }
+
+    debug!("running {:?}", command);
+    let child = process.spawn()?;
+
+    // Dropping the child on timeout kills it.
+    let output = match timeout(limit, child.wait_with_output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            warn!("{:?} timed out", command);
+            return Ok(());
+        }
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("{}: {}", command, line);
+    }
+
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("{}: {}", command, line);
+    }
+
+    if !output.status.success() {
+        warn!("{:?} exited with {}", command, output.status);
+    }
+
+    Ok(())
+}
+
+/// Runs the configured commands one at a time, in the order the changes happened.
+async fn run_hooks<F, Fut>(
+    config: &Config,
+    exec_config: &ExecConfig,
+    mut rx: StatusRx,
+    song_art: F,
+) -> Result<!>
+where
+    F: Fn(SongId) -> Fut,
+    Fut: Future<Output = Result<Option<(BytesMut, Option<String>)>>>,
+{
+    loop {
+        trace!("getting status");
+        let song_status = safe_recv(&mut rx).await?;
+
+        let command = match song_status.change {
+            Change::TrackChanged => exec_config.track_changed.as_ref(),
+            Change::Paused => exec_config.paused.as_ref(),
+            Change::Resumed => exec_config.resumed.as_ref(),
+            Change::Stopped => exec_config.stopped.as_ref(),
+            Change::Seeked => None,
+        };
+
+        if let Some(command) = command {
+            let art = match (&exec_config.art_path, song_status.song_id()) {
+                (Some(art_path), Some(song_id)) => match song_art(song_id).await? {
+                    Some((data, _)) => {
+                        write_atomic(art_path, &data).await?;
+                        Some(art_path.as_path())
+                    }
+                    None => None,
+                },
+                _ => None,
+            };
+
+            let vars = get_vars(&song_status, config);
+            run_hook(command, &vars, art, exec_config.timeout()).await?;
+        }
+    }
+}
+
+pub async fn exec_updater(
+    config: &Config,
+    exec_config: &ExecConfig,
+    mpd: &Mpd,
+    rx: StatusRx,
+) -> Result<!> {
+    run_hooks(config, exec_config, rx, |song_id| mpd.song_art(song_id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::mpd::test_util::playing;
+    use std::env::temp_dir;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::SystemTime;
+    use tokio::sync::broadcast;
+    use tokio::time::{sleep, Instant};
+
+    fn output_path(name: &str) -> PathBuf {
+        let path = temp_dir().join(format!("mpdiscord-exec-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn passes_variables_in_environment() {
+        let config = test_util::config("");
+        let output = output_path("env");
+        let song_status = playing(1, "Title", 0.0, SystemTime::now()).with_change(Change::Paused);
+        let command = format!(
+            "printf '%s|%s|%s' \"$MPDISCORD_TITLE\" \"$MPDISCORD_STATE\" \"$MPDISCORD_ART\" > {:?}",
+            output
+        );
+
+        let art = Path::new("/tmp/cover.jpg");
+        let vars = get_vars(&song_status, &config);
+        run_hook(&command, &vars, Some(art), Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let written = fs::read_to_string(&output).unwrap();
+        let _ = fs::remove_file(&output);
+        assert_eq!(written, "Title|playing|/tmp/cover.jpg");
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_hook() {
+        let output = output_path("timeout");
+        let command = format!("sleep 1; touch {:?}", output);
+
+        let start = Instant::now();
+        run_hook(&command, &Vars::new(), None, Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        sleep(Duration::from_millis(1500)).await;
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn hooks_run_one_at_a_time() {
+        let output = output_path("sequence");
+        let config = test_util::config(&format!(
+            "[exec]\ntrack_changed = \"echo start >> '{path}'; sleep 0.2; echo end >> '{path}'\"\n",
+            path = output.display()
+        ));
+        let (tx, rx) = broadcast::channel(16);
+        for id in 1..=2 {
+            let song_status = playing(id, "Title", 0.0, SystemTime::now());
+            tx.send(song_status.with_change(Change::TrackChanged))
+                .unwrap();
+        }
+        drop(tx);
+
+        // Returns once the channel is closed, after both statuses were handled.
+        let exec_config = config.exec.as_ref().unwrap();
+        let result = run_hooks(&config, exec_config, rx, |_| async { Ok(None) }).await;
+        assert!(result.is_err());
+
+        let written = fs::read_to_string(&output).unwrap();
+        let _ = fs::remove_file(&output);
+        assert_eq!(written, "start\nend\nstart\nend\n");
+    }
+}
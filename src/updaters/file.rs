@@ -0,0 +1,74 @@
+use super::safe_recv;
+use crate::config::Config;
+use crate::conversions::render_text;
+use crate::mpd::{Mpd, PlayState, SongId, SongStatus};
+use crate::util::write_atomic;
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tokio::fs;
+
+fn render(config: &Config, index: usize, song_status: &SongStatus) -> Option<String> {
+    let file_config = &config.file[index];
+
+    if song_status.status.state == PlayState::Stopped {
+        return file_config.stopped_text.clone();
+    }
+
+    render_text(file_config.template.as_deref(), song_status, config)
+}
+
+/// Keeps the `index`th configured file up to date.
+pub async fn file_updater(
+    config: Arc<Config>,
+    index: usize,
+    mpd: Arc<Mpd>,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let file_config = &config.file[index];
+
+    let mut last_text: Option<String> = None;
+    let mut last_art: Option<SongId> = None;
+
+    loop {
+        trace!("getting status");
+        let song_status = safe_recv(&mut rx).await?;
+
+        if let Some(text) = render(&config, index, &song_status) {
+            if last_text.as_ref() != Some(&text) {
+                debug!("writing {}: {}", file_config.path.display(), text);
+                write_atomic(&file_config.path, text.as_bytes()).await?;
+                last_text = Some(text);
+            }
+        }
+
+        if let Some(art_path) = &file_config.art_path {
+            let stopped = song_status.status.state == PlayState::Stopped;
+            let song_id = song_status.song_id().filter(|_| !stopped);
+
+            if song_id != last_art {
+                let art = match song_id {
+                    Some(song_id) => mpd.song_art(song_id).await?,
+                    None => None,
+                };
+
+                if let Some((data, _)) = art {
+                    debug!("writing {}", art_path.display());
+                    write_atomic(art_path, &data).await?;
+                } else if stopped && file_config.stopped_text.is_none() {
+                    trace!("keeping art while stopped");
+                } else {
+                    debug!("removing {}", art_path.display());
+                    match fs::remove_file(art_path).await {
+                        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                        _ => {}
+                    }
+                }
+
+                last_art = song_id;
+            }
+        }
+    }
+}
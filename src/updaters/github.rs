@@ -0,0 +1,39 @@
+use super::Throttle;
+use crate::config::{Config, GitHubConfig};
+use crate::conversions;
+use crate::github::{GitHub, MAX_STATUS_MESSAGE};
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+
+pub async fn github_updater(
+    config: &Config,
+    github_config: &GitHubConfig,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let github = GitHub::new(github_config);
+    let mut throttle = Throttle::new(github_config.rate_limit);
+
+    loop {
+        trace!("getting status");
+        let song_status = throttle.recv(&mut rx).await?;
+
+        let template = github_config.template.as_deref();
+        let text = conversions::status_text(template, &song_status, config, MAX_STATUS_MESSAGE);
+
+        if let Some(text) = text {
+            debug!("setting status: {}", text);
+            let expires_at = github_config
+                .expire_after()
+                .map(|x| conversions::track_end(&song_status) + x);
+            github
+                .set_status(&text, &github_config.emoji, expires_at)
+                .await?;
+            info!("set status");
+        } else {
+            debug!("clearing status");
+            github.clear_status().await?;
+            info!("cleared status");
+        }
+    }
+}
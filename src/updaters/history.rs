@@ -0,0 +1,82 @@
+use super::safe_recv;
+use crate::config::HistoryConfig;
+use crate::history::History;
+use crate::listen::{ListenEvent, ListenTracker};
+use crate::mpd::Song;
+use crate::StatusRx;
+use anyhow::{bail, Result};
+use log::*;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::spawn_blocking;
+
+/// Anything played for less than this is treated as never having been played at all.
+const MIN_LISTENED: Duration = Duration::from_secs(1);
+
+struct Record {
+    song: Song,
+    started_at: SystemTime,
+    listened: Duration,
+    skipped: bool,
+}
+
+/// Owns the database on a blocking thread, so slow writes don't hold up the other sinks.
+fn write_records(config: HistoryConfig, mut records: UnboundedReceiver<Record>) -> Result<()> {
+    let history = History::open(&config)?;
+
+    while let Some(record) = records.blocking_recv() {
+        history.record(
+            &record.song,
+            record.started_at,
+            record.listened,
+            record.skipped,
+        )?;
+        info!("recorded listen");
+    }
+
+    Ok(())
+}
+
+pub async fn history_updater(config: &HistoryConfig, mut rx: StatusRx) -> Result<!> {
+    let (tx, records) = mpsc::unbounded_channel();
+    let config = config.clone();
+    let mut writer = spawn_blocking(move || write_records(config, records));
+    let mut tracker = ListenTracker::default();
+
+    loop {
+        let song_status = tokio::select! {
+            song_status = safe_recv(&mut rx) => song_status?,
+            result = &mut writer => {
+                result??;
+                bail!("history writer stopped");
+            }
+        };
+
+        for event in tracker.update(&song_status) {
+            if let ListenEvent::Finished {
+                song,
+                started_at,
+                listened,
+                skipped,
+            } = event
+            {
+                if listened < MIN_LISTENED {
+                    continue;
+                }
+
+                debug!(
+                    "recording {} ({:?}, skipped: {})",
+                    song.url, listened, skipped
+                );
+                let record = Record {
+                    song,
+                    started_at,
+                    listened,
+                    skipped,
+                };
+                // If the writer has failed, its error is picked up on the next iteration.
+                let _ = tx.send(record);
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use super::safe_recv;
+use crate::config::{Config, IcecastConfig, StreamServer};
+use crate::conversions::get_vars;
+use crate::mpd::Change;
+use crate::template;
+use crate::StatusRx;
+use anyhow::{bail, Result};
+use log::*;
+use reqwest::header::USER_AGENT;
+use reqwest::{Client, Response};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const DEFAULT_TEMPLATE: &str = "{artist} - {title}";
+
+/// Percent-encodes everything but unreserved characters, since not every server decodes `+` in
+/// queries as a space.
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{:02X}", byte).unwrap();
+        }
+    }
+    encoded
+}
+
+async fn send(client: &Client, server: &IcecastConfig, song: &str) -> Result<()> {
+    let base = server.url.trim_end_matches('/');
+
+    let request = match server.server {
+        StreamServer::Icecast => {
+            let mount = match &server.mount {
+                Some(mount) => mount,
+                None => bail!("no mount configured for {}", server.url),
+            };
+            let url = format!(
+                "{}/admin/metadata?mode=updinfo&mount={}&charset=UTF-8&song={}",
+                base,
+                url_encode(mount),
+                url_encode(song)
+            );
+            client
+                .get(url)
+                .basic_auth(&server.username, Some(&server.password))
+        }
+        StreamServer::Shoutcast => {
+            let mut url = format!(
+                "{}/admin.cgi?mode=updinfo&pass={}&song={}",
+                base,
+                url_encode(&server.password),
+                url_encode(song)
+            );
+            if let Some(sid) = server.sid {
+                write!(url, "&sid={}", sid).unwrap();
+            }
+            // Shoutcast v1 answers anything that doesn't look like a browser with the stream.
+            client
+                .get(url)
+                .header(USER_AGENT, "Mozilla/5.0 (mpdiscord)")
+        }
+    };
+
+    // The URL carries the Shoutcast password, so keep it out of errors and logs.
+    request
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+        .map_err(reqwest::Error::without_url)?;
+    Ok(())
+}
+
+/// Sends the update, retrying with exponential backoff.
+async fn deliver(client: &Client, server: &IcecastConfig, song: &str) -> Result<()> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+
+    loop {
+        match send(client, server, song).await {
+            Ok(()) => break Ok(()),
+            Err(err) if attempt < server.retries => {
+                debug!("updating {} failed, retrying: {}", server.url, err);
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => break Err(err),
+        }
+    }
+}
+
+/// Keeps the metadata of the `index`th configured stream server in sync.
+pub async fn icecast_updater(config: Arc<Config>, index: usize, mut rx: StatusRx) -> Result<!> {
+    let server = &config.icecast[index];
+    let client = Client::builder().timeout(server.timeout()).build()?;
+
+    loop {
+        trace!("getting status");
+        let song_status = safe_recv(&mut rx).await?;
+
+        if song_status.change != Change::TrackChanged || song_status.song.is_none() {
+            continue;
+        }
+
+        let template = server.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        let song = template::render(template, &get_vars(&song_status, &config));
+
+        debug!("updating {}: {}", server.url, song);
+        match deliver(&client, server, &song).await {
+            Ok(()) => info!("updated metadata on {}", server.url),
+            Err(err) => warn!("couldn't update metadata on {}: {}", server.url, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::util::test_util::mock_server;
+    use axum::extract::Query;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Router;
+    use std::collections::HashMap;
+
+    async fn admin(Query(query): Query<HashMap<String, String>>) -> StatusCode {
+        if query.get("pass").map(String::as_str) == Some("secret") {
+            StatusCode::OK
+        } else {
+            StatusCode::UNAUTHORIZED
+        }
+    }
+
+    async fn config(password: &str) -> Config {
+        let url = mock_server(Router::new().route("/admin.cgi", get(admin))).await;
+        test_util::config(&format!(
+            "[[icecast]]\nurl = \"{}\"\nserver = \"shoutcast\"\npassword = \"{}\"\n",
+            url, password
+        ))
+    }
+
+    #[tokio::test]
+    async fn shoutcast() {
+        let config = config("secret").await;
+        send(&Client::new(), &config.icecast[0], "Title - Artist")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors_hide_password() {
+        let config = config("hunter2").await;
+        let err = send(&Client::new(), &config.icecast[0], "Title - Artist")
+            .await
+            .unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("401"), "{}", message);
+        assert!(!message.contains("hunter2"), "{}", message);
+    }
+}
@@ -0,0 +1,170 @@
+use super::safe_recv;
+use crate::config::{Config, IrcConfig};
+use crate::conversions;
+use crate::mpd::{Change, Mpd, PlayState, SongStatus};
+use crate::StatusRx;
+use anyhow::{bail, Result};
+use futures_util::StreamExt;
+use irc::client::prelude::{Client, Command, Config as ClientConfig};
+use log::*;
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+/// Nothing is announced or answered while stopped, even though MPD still has a current song.
+fn get_text(irc_config: &IrcConfig, song_status: &SongStatus, config: &Config) -> Option<String> {
+    if song_status.status.state == PlayState::Stopped {
+        return None;
+    }
+
+    conversions::render_text(irc_config.template.as_deref(), song_status, config)
+}
+
+fn reply(irc_config: &IrcConfig, song_status: Option<&SongStatus>, config: &Config) -> String {
+    let song_status = match song_status {
+        Some(song_status) => song_status,
+        None => return "Nothing is playing.".to_string(),
+    };
+
+    match get_text(irc_config, song_status, config) {
+        Some(text) => match conversions::get_art_url(song_status, config) {
+            Some(art_url) => format!("{} ({})", text, art_url),
+            None => text,
+        },
+        None => "Nothing is playing.".to_string(),
+    }
+}
+
+fn is_command(text: &str, command: &str) -> bool {
+    text.split_whitespace().next() == Some(command)
+}
+
+pub async fn irc_updater(
+    config: &Config,
+    irc_config: &IrcConfig,
+    mpd: &Mpd,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let mut client = Client::from_config(ClientConfig {
+        server: Some(irc_config.server.clone()),
+        port: irc_config.port,
+        use_tls: Some(irc_config.tls),
+        nickname: Some(irc_config.nickname.clone()),
+        password: irc_config.password.clone(),
+        channels: irc_config.channels.clone(),
+        ..ClientConfig::default()
+    })
+    .await?;
+    client.identify()?;
+    info!("connected to {}", irc_config.server);
+
+    let mut stream = client.stream()?;
+    // Only changes are broadcast, so this would stay empty until the next one otherwise.
+    let mut current = Some(mpd.song_status(Change::TrackChanged).await?);
+    let mut last_reply: HashMap<String, Instant> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            message = stream.next() => {
+                let message = match message {
+                    Some(message) => message?,
+                    None => bail!("connection closed"),
+                };
+
+                let text = match &message.command {
+                    Command::PRIVMSG(_, text) => text,
+                    _ => continue,
+                };
+
+                if !is_command(text, &irc_config.command) {
+                    continue;
+                }
+
+                let target = match message.response_target() {
+                    Some(target) => target.to_string(),
+                    None => continue,
+                };
+
+                if last_reply.get(&target).is_some_and(|x| x.elapsed() < irc_config.cooldown()) {
+                    debug!("{} is cooling down", target);
+                    continue;
+                }
+
+                let reply = reply(irc_config, current.as_ref(), config);
+                debug!("replying in {}: {}", target, reply);
+                client.send_privmsg(&target, reply)?;
+                last_reply.insert(target, Instant::now());
+            }
+            song_status = safe_recv(&mut rx) => {
+                let song_status = song_status?;
+
+                if irc_config.announce && song_status.change == Change::TrackChanged {
+                    if let Some(text) = get_text(irc_config, &song_status, config) {
+                        debug!("announcing: {}", text);
+                        for channel in &irc_config.channels {
+                            client.send_privmsg(channel, format!("Now playing: {}", text))?;
+                        }
+                        info!("announced track change");
+                    }
+                }
+
+                current = Some(song_status);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::mpd::test_util::playing;
+    use std::time::SystemTime;
+
+    const IRC: &str = "[irc]\nserver = \"irc.example.com\"\nnickname = \"bot\"\nchannels = []\n";
+
+    fn song_status() -> SongStatus {
+        playing(1, "Title", 0.0, SystemTime::now()).with_change(Change::TrackChanged)
+    }
+
+    #[test]
+    fn command_parsing() {
+        assert!(is_command("!song", "!song"));
+        assert!(is_command("  !song please", "!song"));
+        assert!(!is_command("!songs", "!song"));
+        assert!(!is_command("what's the !song", "!song"));
+        assert!(!is_command("", "!song"));
+    }
+
+    #[test]
+    fn replies() {
+        let config = test_util::config(IRC);
+        let irc_config = config.irc.as_ref().unwrap();
+        let mut song_status = song_status();
+
+        assert_eq!(reply(irc_config, None, &config), "Nothing is playing.");
+        assert_eq!(
+            reply(irc_config, Some(&song_status), &config),
+            "Title - Unknown Artist"
+        );
+
+        song_status.status.state = PlayState::Stopped;
+        assert_eq!(
+            reply(irc_config, Some(&song_status), &config),
+            "Nothing is playing."
+        );
+    }
+
+    #[test]
+    fn reply_template_and_art() {
+        let config = test_util::config(&format!(
+            "{}template = \"{{title}} ({{state}})\"\n[web]\nlisten_addr = \"127.0.0.1:8080\"\npublic_addr = \"https://example.com\"\n",
+            IRC
+        ));
+        let irc_config = config.irc.as_ref().unwrap();
+
+        assert_eq!(
+            reply(irc_config, Some(&song_status()), &config),
+            "Title (playing) (https://example.com/art/1)"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use super::queued_updater;
+use crate::config::LastFmConfig;
+use crate::lastfm::{LastFm, Rejected, Scrobble, MAX_BATCH};
+use crate::listen::ListenEvent;
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+use std::time::SystemTime;
+
+pub async fn lastfm_updater(config: &LastFmConfig, rx: StatusRx) -> Result<!> {
+    let lastfm = &LastFm::new(config);
+
+    queued_updater(
+        "scrobbles",
+        &config.queue_path,
+        MAX_BATCH,
+        rx,
+        |event| async move {
+            match event {
+                ListenEvent::NowPlaying(song) => {
+                    if let Some(scrobble) = Scrobble::from_song(&song, SystemTime::now()) {
+                        debug!("now playing: {} - {}", scrobble.artist, scrobble.track);
+                        match lastfm.update_now_playing(&scrobble).await {
+                            Ok(()) => info!("updated now playing"),
+                            Err(err) => warn!("couldn't update now playing: {}", err),
+                        }
+                    }
+                    None
+                }
+                ListenEvent::Listened {
+                    song, started_at, ..
+                } => {
+                    let scrobble = Scrobble::from_song(&song, started_at);
+                    match &scrobble {
+                        Some(scrobble) => {
+                            debug!("scrobbling: {} - {}", scrobble.artist, scrobble.track)
+                        }
+                        None => debug!("not scrobbling song without artist and title"),
+                    }
+                    scrobble
+                }
+                ListenEvent::Finished { .. } => None,
+            }
+        },
+        |scrobbles| async move { lastfm.scrobble(&scrobbles).await },
+        |err| err.is::<Rejected>(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LastFmConfig;
+    use crate::queue::DurableQueue;
+    use crate::updaters::flush;
+    use crate::util::test_util::mock_server;
+    use axum::extract::Form;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::json;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    fn scrobble(track: &str) -> Scrobble {
+        Scrobble {
+            artist: "Artist".into(),
+            track: track.into(),
+            album: None,
+            album_artist: None,
+            track_number: None,
+            duration: None,
+            timestamp: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_scrobbles_are_moved_aside() {
+        let scrobbled = Arc::new(Mutex::new(vec![]));
+        let handler = {
+            let scrobbled = scrobbled.clone();
+            move |Form(form): Form<Vec<(String, String)>>| async move {
+                let tracks: Vec<_> = form
+                    .into_iter()
+                    .filter(|(name, _)| name.starts_with("track["))
+                    .map(|(_, value)| value)
+                    .collect();
+
+                if tracks.iter().any(|x| x == "Bad") {
+                    return Json(json!({ "error": 6, "message": "Invalid parameters" }));
+                }
+
+                scrobbled.lock().unwrap().extend(tracks);
+                Json(json!({ "scrobbles": {} }))
+            }
+        };
+
+        let queue_path = temp_dir().join(format!("mpdiscord-lastfm-{}.json", process::id()));
+        let lastfm = LastFm::new(&LastFmConfig {
+            api_key: "key".into(),
+            api_secret: "secret".into(),
+            session_key: Some("session".into()),
+            api_base: mock_server(Router::new().route("/", post(handler))).await,
+            queue_path: queue_path.clone(),
+        });
+
+        let mut queue = DurableQueue::open(&queue_path).await.unwrap();
+        let mut rejected = queue.open_rejected().await.unwrap();
+        for track in ["First", "Bad", "Second"] {
+            queue.push(scrobble(track)).await.unwrap();
+        }
+
+        let lastfm = &lastfm;
+        let emptied = flush(
+            "scrobbles",
+            &mut queue,
+            &mut rejected,
+            MAX_BATCH,
+            &|scrobbles: Vec<Scrobble>| async move { lastfm.scrobble(&scrobbles).await },
+            &|err: &anyhow::Error| err.is::<Rejected>(),
+        )
+        .await
+        .unwrap();
+        let rejected_tracks: Vec<_> = rejected.items().iter().map(|x| &x.track).collect();
+        let _ = remove_file(queue.path());
+        let _ = remove_file(rejected.path());
+
+        assert!(emptied);
+        assert_eq!(*scrobbled.lock().unwrap(), ["First", "Second"]);
+        assert_eq!(rejected_tracks, ["Bad"]);
+    }
+}
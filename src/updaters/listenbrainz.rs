@@ -0,0 +1,46 @@
+use super::queued_updater;
+use crate::config::ListenBrainzConfig;
+use crate::listen::ListenEvent;
+use crate::listenbrainz::{Listen, ListenBrainz, MAX_BATCH};
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+
+pub async fn listenbrainz_updater(config: &ListenBrainzConfig, rx: StatusRx) -> Result<!> {
+    let listenbrainz = &ListenBrainz::new(config);
+
+    queued_updater(
+        "listens",
+        &config.queue_path,
+        MAX_BATCH,
+        rx,
+        |event| async move {
+            match event {
+                ListenEvent::NowPlaying(song) => {
+                    if let Some(listen) = Listen::from_song(&song, None) {
+                        debug!("playing now: {}", listen.track_metadata.track_name);
+                        match listenbrainz.playing_now(&listen).await {
+                            Ok(()) => info!("updated playing now"),
+                            Err(err) => warn!("couldn't update playing now: {}", err),
+                        }
+                    }
+                    None
+                }
+                ListenEvent::Listened {
+                    song, started_at, ..
+                } => {
+                    let listen = Listen::from_song(&song, Some(started_at));
+                    match &listen {
+                        Some(listen) => debug!("submitting: {}", listen.track_metadata.track_name),
+                        None => debug!("not submitting song without artist and title"),
+                    }
+                    listen
+                }
+                ListenEvent::Finished { .. } => None,
+            }
+        },
+        |listens| async move { listenbrainz.listens(&listens).await },
+        ListenBrainz::is_rejected,
+    )
+    .await
+}
@@ -1,41 +1,482 @@
-use super::safe_recv;
-use crate::config::Config;
+use super::Throttle;
+use crate::config::{Config, MastodonConfig, MastodonMode};
 use crate::conversions;
-use crate::mastodon::Mastodon;
+use crate::mpd::{Change, Mpd, PlayState, SongStatus};
+use crate::profile::{self, Field, Profile, ProfileBackend};
 use crate::StatusRx;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::*;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
 
-pub async fn mastodon_updater(config: Arc<Config>, mut rx: StatusRx) -> Result<!> {
-    let mastodon = Mastodon::new(&config);
+struct PendingPost {
+    song_status: SongStatus,
+    /// Playing time before the most recent resume.
+    listened: Duration,
+    playing_since: Option<Instant>,
+}
+
+impl PendingPost {
+    fn new(song_status: SongStatus) -> Self {
+        let playing = song_status.status.state == PlayState::Playing;
+
+        Self {
+            song_status,
+            listened: Duration::ZERO,
+            playing_since: playing.then(Instant::now),
+        }
+    }
+
+    /// Counts the time the track actually spent playing, so seeking ahead doesn't count.
+    fn update(&mut self, song_status: SongStatus) {
+        let playing = song_status.status.state == PlayState::Playing;
+
+        match self.playing_since {
+            Some(since) if !playing => {
+                self.listened += since.elapsed();
+                self.playing_since = None;
+            }
+            None if playing => self.playing_since = Some(Instant::now()),
+            _ => {}
+        }
+
+        self.song_status = song_status;
+    }
+
+    /// When the track will have been played for long enough, if it's playing at all.
+    fn deadline(&self, min_listen: Duration) -> Option<Instant> {
+        let since = self.playing_since?;
+        Some(since + min_listen.saturating_sub(self.listened))
+    }
+}
+
+const MARKER: &str = "Last listening to:";
+
+/// The part of the note written by the user, before the now playing line.
+fn user_bio(note: &str) -> &str {
+    note.split(MARKER).next().unwrap_or("").trim_end()
+}
+
+/// Which part of the profile a write replaced.
+#[derive(Clone, Copy)]
+enum Written {
+    Note,
+    Fields,
+}
+
+/// The profile as we last saw it, so it doesn't have to be fetched before every update.
+struct AccountCache {
+    profile: Profile,
+    /// The part of the note written by the user, which every bio update starts from.
+    bio: String,
+    fetched_at: Instant,
+    refresh: Duration,
+}
+
+impl AccountCache {
+    fn new(profile: Profile, refresh: Duration) -> Self {
+        Self {
+            bio: user_bio(&profile.note).to_string(),
+            profile,
+            fetched_at: Instant::now(),
+            refresh,
+        }
+    }
+
+    fn adopt(&mut self, profile: &Profile) {
+        info!("profile was edited externally, adopting changes");
+        self.bio = user_bio(&profile.note).to_string();
+    }
+
+    /// Returns the cached profile, re-fetching it once it's old enough that the user might have
+    /// edited it elsewhere.
+    async fn get(&mut self, backend: &dyn ProfileBackend) -> Result<&Profile> {
+        if self.fetched_at.elapsed() >= self.refresh {
+            trace!("refreshing profile");
+            let profile = backend.profile().await?;
+
+            if profile.note != self.profile.note || profile.fields != self.profile.fields {
+                self.adopt(&profile);
+            }
+
+            self.profile = profile;
+            self.fetched_at = Instant::now();
+        }
+
+        Ok(&self.profile)
+    }
+
+    /// Stores the profile a write returned. The part that wasn't written should be unchanged,
+    /// so if it isn't, it was edited elsewhere since we last saw it.
+    fn store(&mut self, profile: Profile, written: Written) {
+        let diverged = match written {
+            Written::Note => profile.fields != self.profile.fields,
+            Written::Fields => profile.note != self.profile.note,
+        };
+        if diverged {
+            self.adopt(&profile);
+        }
+
+        self.profile = profile;
+    }
+}
+
+async fn update_bio(
+    backend: &dyn ProfileBackend,
+    cache: &mut AccountCache,
+    notice: &str,
+) -> Result<()> {
+    cache.get(backend).await?;
+
+    // Only the now playing line is ours to shorten.
+    let mut new_bio = format!("{}\n\n{} ", cache.bio, MARKER);
+    let room = match backend.max_note().checked_sub(new_bio.chars().count()) {
+        Some(room) => room,
+        None => bail!("the bio is too long to add the now playing line to"),
+    };
+    new_bio.extend(notice.chars().take(room));
+
+    debug!("updating: {}", notice);
+    let profile = backend.set_note(&new_bio).await?;
+    cache.store(profile, Written::Note);
+    info!("set bio");
+
+    Ok(())
+}
+
+async fn update_field(
+    backend: &dyn ProfileBackend,
+    cache: &mut AccountCache,
+    account: &MastodonConfig,
+    notice: &str,
+) -> Result<()> {
+    let mut fields = cache.get(backend).await?.fields.clone();
+
+    let value: String = notice.chars().take(backend.max_field_value()).collect();
+
+    if let Some(field) = fields.iter_mut().find(|x| x.name == account.field_name) {
+        field.value = value;
+    } else if fields.len() < backend.max_fields() {
+        fields.push(Field {
+            name: account.field_name.clone(),
+            value,
+        });
+    } else {
+        bail!("no room for a {:?} profile field", account.field_name);
+    }
+
+    debug!("updating field: {}", notice);
+    let profile = backend.set_fields(&fields).await?;
+    cache.store(profile, Written::Fields);
+    info!("set profile field");
+
+    Ok(())
+}
+
+async fn post(
+    backend: &dyn ProfileBackend,
+    mpd: &Mpd,
+    account: &MastodonConfig,
+    config: &Config,
+    song_status: &SongStatus,
+) -> Result<()> {
+    let post_config = &account.post;
+
+    let notice = if let Some(notice) =
+        conversions::render_text(account.template.as_deref(), song_status, config)
+    {
+        notice
+    } else {
+        debug!("(no song)");
+        return Ok(());
+    };
+
+    let mut status = format!("Now listening to: {}", notice);
+
+    if !post_config.hashtags.is_empty() {
+        let hashtags: Vec<_> = post_config
+            .hashtags
+            .iter()
+            .map(|x| format!("#{}", x.trim_start_matches('#')))
+            .collect();
+        status.push_str("\n\n");
+        status.push_str(&hashtags.join(" "));
+    }
+
+    let mut media_ids = vec![];
+
+    if post_config.attach_cover {
+        if let Some(song_id) = song_status.song_id() {
+            trace!("getting cover");
+            if let Some((data, mime)) = mpd.song_art(song_id).await? {
+                trace!("uploading cover");
+                media_ids.push(backend.upload_media(data.to_vec(), mime.as_deref()).await?);
+            }
+        }
+    }
+
+    debug!("posting: {}", notice);
+    backend
+        .post(&status, post_config.visibility, &media_ids)
+        .await?;
+    info!("posted status");
+
+    Ok(())
+}
+
+/// Keeps the `index`th configured account up to date.
+pub async fn mastodon_updater(
+    config: Arc<Config>,
+    index: usize,
+    mpd: Arc<Mpd>,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let account = &config.mastodon[index];
+    let backend = profile::backend(account);
+    let mut throttle = Throttle::new(account.rate_limit);
 
-    let account = mastodon.account().await?;
-    info!("logged in as {}", account.acct);
+    let bio_mode = account.modes.contains(&MastodonMode::Bio);
+    let field_mode = account.modes.contains(&MastodonMode::Field);
+    let post_mode = account.modes.contains(&MastodonMode::Post);
+    let min_listen = account.post.min_listen();
+
+    let mut pending_post: Option<PendingPost> = None;
+    let mut last_post: Option<Instant> = None;
+
+    let profile = backend.profile().await?;
+    info!("logged in as {} on {}", profile.acct, account.instance);
+
+    let mut cache = AccountCache::new(profile, account.refresh());
 
     loop {
+        let deadline = pending_post.as_ref().and_then(|x| x.deadline(min_listen));
+
         trace!("getting status");
-        let song_status = safe_recv(&mut rx).await?;
+        let song_status = tokio::select! {
+            song_status = throttle.recv(&mut rx) => song_status?,
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let pending = pending_post.take().unwrap();
 
-        if let Some(notice) = conversions::get_text(&song_status) {
-            trace!("getting mastodon account");
-            let account = mastodon.account().await?;
+                if last_post.is_some_and(|x| x.elapsed() < account.post.min_interval()) {
+                    info!("skipping post, last one was too recent");
+                } else if let Err(err) =
+                    post(&*backend, &mpd, account, &config, &pending.song_status).await
+                {
+                    warn!("couldn't post status: {}", err);
+                } else {
+                    last_post = Some(Instant::now());
+                }
 
-            let bio = account
-                .source
-                .note
-                .split("Last listening to:")
-                .next()
-                .unwrap_or("")
-                .trim_end();
+                continue;
+            }
+        };
+
+        if post_mode {
+            match song_status.change {
+                Change::TrackChanged => {
+                    pending_post = Some(PendingPost::new(song_status.clone()));
+                }
+                Change::Stopped => pending_post = None,
+                _ => {
+                    if let Some(pending) = &mut pending_post {
+                        pending.update(song_status.clone());
+                    }
+                }
+            }
+        }
+
+        if song_status.change != Change::TrackChanged {
+            debug!("ignoring {:?}", song_status.change);
+            continue;
+        }
 
-            let new_bio = format!("{}\n\nLast listening to: {}", bio, notice);
+        if let Some(notice) =
+            conversions::render_text(account.template.as_deref(), &song_status, &config)
+        {
+            if bio_mode {
+                update_bio(&*backend, &mut cache, &notice).await?;
+            }
 
-            debug!("updating: {}", notice);
-            mastodon.set_bio(&new_bio).await?;
-            info!("set bio");
+            if field_mode {
+                update_field(&*backend, &mut cache, account, &notice).await?;
+            }
         } else {
             debug!("(no song)");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Visibility;
+    use crate::mpd::test_util::playing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+    use tokio::time::advance;
+
+    const REFRESH: Duration = Duration::from_secs(30 * 60);
+
+    /// Keeps the profile in memory, standing in for the server.
+    struct Memory {
+        profile: Mutex<Profile>,
+        reads: AtomicUsize,
+    }
+
+    impl Memory {
+        fn new(note: &str) -> Self {
+            Self {
+                profile: Mutex::new(Profile {
+                    acct: "user".into(),
+                    note: note.into(),
+                    fields: vec![],
+                }),
+                reads: AtomicUsize::new(0),
+            }
+        }
+
+        fn note(&self) -> String {
+            self.profile.lock().unwrap().note.clone()
+        }
+
+        /// Edits the note the way the web interface would, behind the cache's back.
+        fn edit(&self, note: &str) {
+            self.profile.lock().unwrap().note = note.to_string();
+        }
+
+        fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ProfileBackend for Memory {
+        async fn profile(&self) -> Result<Profile> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(self.profile.lock().unwrap().clone())
+        }
+
+        async fn set_note(&self, note: &str) -> Result<Profile> {
+            let mut profile = self.profile.lock().unwrap();
+            profile.note = note.to_string();
+            Ok(profile.clone())
+        }
+
+        async fn set_fields(&self, fields: &[Field]) -> Result<Profile> {
+            let mut profile = self.profile.lock().unwrap();
+            profile.fields = fields.to_vec();
+            Ok(profile.clone())
+        }
+
+        async fn upload_media(&self, _: Vec<u8>, _: Option<&str>) -> Result<String> {
+            bail!("media isn't supported")
+        }
+
+        async fn post(&self, _: &str, _: Visibility, _: &[String]) -> Result<()> {
+            bail!("posts aren't supported")
+        }
+
+        fn max_note(&self) -> usize {
+            40
+        }
+
+        fn max_fields(&self) -> usize {
+            4
+        }
+
+        fn max_field_value(&self) -> usize {
+            255
+        }
+    }
+
+    fn cache(backend: &Memory) -> AccountCache {
+        let profile = backend.profile.lock().unwrap().clone();
+        AccountCache::new(profile, REFRESH)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn profile_is_only_refetched_periodically() {
+        let backend = Memory::new("Hello");
+        let mut cache = cache(&backend);
+
+        update_bio(&backend, &mut cache, "First").await.unwrap();
+        update_bio(&backend, &mut cache, "Second").await.unwrap();
+        assert_eq!(backend.reads(), 0);
+        assert_eq!(backend.note(), "Hello\n\nLast listening to: Second");
+
+        backend.edit("Edited\n\nLast listening to: Second");
+        advance(REFRESH).await;
+        update_bio(&backend, &mut cache, "Third").await.unwrap();
+        assert_eq!(backend.reads(), 1);
+        assert_eq!(backend.note(), "Edited\n\nLast listening to: Third");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn edits_seen_in_write_responses_are_adopted() {
+        let backend = Memory::new("Hello");
+        let mut cache = cache(&backend);
+        let account: MastodonConfig =
+            toml::from_str("token = \"token\"\nmodes = [\"bio\", \"field\"]").unwrap();
+
+        backend.edit("Edited");
+        update_field(&backend, &mut cache, &account, "First")
+            .await
+            .unwrap();
+        update_bio(&backend, &mut cache, "First").await.unwrap();
+
+        assert_eq!(backend.reads(), 0);
+        assert_eq!(backend.note(), "Edited\n\nLast listening to: First");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_now_playing_is_shortened() {
+        let backend = Memory::new("Hello");
+        let mut cache = cache(&backend);
+
+        update_bio(&backend, &mut cache, "A song with a rather long title")
+            .await
+            .unwrap();
+        assert_eq!(backend.note(), "Hello\n\nLast listening to: A song with a ");
+
+        backend.edit(&"x".repeat(30));
+        advance(REFRESH).await;
+        assert!(update_bio(&backend, &mut cache, "Song").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seeking_ahead_doesnt_count_as_listening() {
+        let min_listen = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut pending = PendingPost::new(
+            playing(1, "Song", 0.0, SystemTime::now()).with_change(Change::TrackChanged),
+        );
+
+        advance(Duration::from_secs(5)).await;
+        pending.update(playing(1, "Song", 120.0, SystemTime::now()).with_change(Change::Seeked));
+
+        assert_eq!(pending.deadline(min_listen), Some(start + min_listen));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_time_doesnt_count_as_listening() {
+        let min_listen = Duration::from_secs(30);
+        let start = Instant::now();
+        let song_status = playing(1, "Song", 0.0, SystemTime::now());
+        let mut pending = PendingPost::new(song_status.clone().with_change(Change::TrackChanged));
+
+        advance(Duration::from_secs(10)).await;
+        let mut paused = song_status.clone().with_change(Change::Paused);
+        paused.status.state = PlayState::Paused;
+        pending.update(paused);
+        assert_eq!(pending.deadline(min_listen), None);
+
+        advance(Duration::from_secs(60)).await;
+        pending.update(song_status.with_change(Change::Resumed));
+        assert_eq!(
+            pending.deadline(min_listen),
+            Some(start + Duration::from_secs(90))
+        );
+    }
+}
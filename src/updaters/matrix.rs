@@ -0,0 +1,86 @@
+use super::Throttle;
+use crate::config::{Config, MatrixConfig};
+use crate::conversions;
+use crate::matrix::{ImageInfo, Matrix, Message};
+use crate::mpd::{Change, Mpd, SongStatus};
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+
+async fn post(
+    matrix: &mut Matrix,
+    mpd: &Mpd,
+    matrix_config: &MatrixConfig,
+    room_id: &str,
+    song_status: &SongStatus,
+    text: &str,
+) -> Result<()> {
+    debug!("posting: {}", text);
+    matrix
+        .send_message(room_id, &Message::Text { body: text })
+        .await?;
+
+    if matrix_config.attach_cover {
+        if let Some(song_id) = song_status.song_id() {
+            trace!("getting cover");
+            if let Some((data, mime)) = mpd.song_art(song_id).await? {
+                trace!("uploading cover");
+                let size = data.len();
+                let url = matrix.upload(data.to_vec(), mime.as_deref()).await?;
+
+                let image = Message::Image {
+                    body: "cover",
+                    url: &url,
+                    info: ImageInfo {
+                        mimetype: mime.as_deref(),
+                        size,
+                    },
+                };
+                matrix.send_message(room_id, &image).await?;
+            }
+        }
+    }
+
+    info!("posted to {}", room_id);
+    Ok(())
+}
+
+pub async fn matrix_updater(
+    config: &Config,
+    matrix_config: &MatrixConfig,
+    mpd: &Mpd,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let mut matrix = Matrix::new(matrix_config)?;
+    let mut throttle = Throttle::new(matrix_config.rate_limit);
+
+    let user_id = matrix.whoami().await?;
+    info!("logged in as {}", user_id);
+
+    loop {
+        trace!("getting status");
+        let song_status = throttle.recv(&mut rx).await?;
+
+        let text = match song_status.change {
+            Change::TrackChanged => {
+                conversions::render_text(matrix_config.template.as_deref(), &song_status, config)
+            }
+            Change::Stopped => None,
+            change => {
+                debug!("ignoring {:?}", change);
+                continue;
+            }
+        };
+
+        let status_msg = text.as_deref().unwrap_or("");
+        debug!("setting status: {}", status_msg);
+        matrix.set_presence(&user_id, "online", status_msg).await?;
+        info!("set presence");
+
+        if let (Change::TrackChanged, Some(room_id), Some(text)) =
+            (song_status.change, &matrix_config.room_id, &text)
+        {
+            post(&mut matrix, mpd, matrix_config, room_id, &song_status, text).await?;
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use super::safe_recv;
+use crate::config::{Config, MprisConfig};
+use crate::conversions::get_art_url;
+use crate::mpd::{Change, Mpd, SongId, SongStatus};
+use crate::mpris::{Player, Root, BUS_NAME, PATH};
+use crate::util::write_atomic;
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use zbus::connection;
+
+/// Covers written to `art_dir`, named after the song so that clients don't show a stale cached
+/// image for a new `file://` URL.
+struct ArtCache<'a> {
+    dir: &'a Path,
+    current: Option<(SongId, Option<PathBuf>)>,
+}
+
+impl<'a> ArtCache<'a> {
+    fn new(dir: &'a Path) -> Self {
+        Self { dir, current: None }
+    }
+
+    async fn url(&mut self, mpd: &Mpd, song_status: &SongStatus) -> Result<Option<String>> {
+        let song_id = match song_status.song_id() {
+            Some(song_id) => song_id,
+            None => return Ok(None),
+        };
+
+        if let Some((cached_id, path)) = &self.current {
+            if *cached_id == song_id {
+                return Ok(path.as_ref().map(|x| format!("file://{}", x.display())));
+            }
+        }
+
+        if let Some((_, Some(old_path))) = self.current.take() {
+            match fs::remove_file(&old_path).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        let path = match mpd.song_art(song_id).await? {
+            Some((data, _)) => {
+                let path = self.dir.join(format!("cover-{}", song_id.0));
+                debug!("writing {}", path.display());
+                write_atomic(&path, &data).await?;
+                Some(path)
+            }
+            None => None,
+        };
+
+        let url = path.as_ref().map(|x| format!("file://{}", x.display()));
+        self.current = Some((song_id, path));
+        Ok(url)
+    }
+}
+
+pub async fn mpris_updater(
+    config: &Config,
+    mpris_config: &MprisConfig,
+    mpd: Arc<Mpd>,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let builder = match &mpris_config.bus_address {
+        Some(address) => connection::Builder::address(address.as_str())?,
+        None => connection::Builder::session()?,
+    };
+    let player = Player {
+        mpd: mpd.clone(),
+        song_status: None,
+        art_url: None,
+    };
+    let connection = builder
+        .name(BUS_NAME)?
+        .serve_at(PATH, Root)?
+        .serve_at(PATH, player)?
+        .build()
+        .await?;
+    let player = connection
+        .object_server()
+        .interface::<_, Player>(PATH)
+        .await?;
+    info!("registered {}", BUS_NAME);
+
+    let mut art_cache = mpris_config.art_dir.as_deref().map(ArtCache::new);
+
+    loop {
+        trace!("getting status");
+        let song_status = safe_recv(&mut rx).await?;
+        let change = song_status.change;
+
+        let mut art_url = get_art_url(&song_status, config);
+        if art_url.is_none() {
+            if let Some(art_cache) = &mut art_cache {
+                art_url = art_cache
+                    .url(&mpd, &song_status)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("couldn't cache cover: {}", err);
+                        None
+                    });
+            }
+        }
+
+        let ctxt = player.signal_context();
+        let mut iface = player.get_mut().await;
+        iface.song_status = Some(song_status);
+        iface.art_url = art_url;
+
+        iface.playback_status_changed(ctxt).await?;
+        iface.volume_changed(ctxt).await?;
+        match change {
+            Change::TrackChanged | Change::Stopped => {
+                iface.metadata_changed(ctxt).await?;
+                iface.can_seek_changed(ctxt).await?;
+            }
+            Change::Seeked => {
+                Player::seeked(ctxt, iface.position_micros()).await?;
+            }
+            Change::Paused | Change::Resumed => {}
+        }
+        debug!("updated player: {:?}", change);
+    }
+}
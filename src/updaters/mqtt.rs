@@ -0,0 +1,224 @@
+use super::safe_recv;
+use crate::config::{Config, MqttConfig};
+use crate::conversions::{get_art_url, get_artist, state_name};
+use crate::mpd::SongStatus;
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+fn now_playing(song_status: &SongStatus, config: &Config) -> Value {
+    let song = song_status.song.as_ref();
+
+    json!({
+        "state": state_name(song_status.status.state),
+        "title": song.and_then(|x| x.title()),
+        "artist": song.and_then(get_artist),
+        "album": song.and_then(|x| x.album()),
+        "file": song.map(|x| &x.url),
+        "elapsed": song_status.position.map(|x| x.elapsed.as_secs()),
+        "duration": song_status.status.duration.map(|x| x.as_secs()),
+        "art_url": get_art_url(song_status, config),
+    })
+}
+
+struct Publisher<'a> {
+    client: AsyncClient,
+    mqtt_config: &'a MqttConfig,
+}
+
+impl Publisher<'_> {
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.mqtt_config.base_topic, name)
+    }
+
+    async fn publish(&self, topic: String, payload: impl Into<Vec<u8>>) -> Result<()> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .await?;
+        Ok(())
+    }
+
+    async fn publish_state(&self, state: &Value) -> Result<()> {
+        self.publish(self.topic("state"), state.to_string()).await?;
+
+        for (name, payload) in topic_payloads(state) {
+            self.publish(self.topic(name), payload).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Announces the sensors to Home Assistant.
+    async fn publish_discovery(&self) -> Result<()> {
+        for (topic, config) in discovery_configs(self.mqtt_config) {
+            self.publish(topic, config.to_string()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Plain text payloads for the topics of single values, for consumers that can't parse JSON.
+fn topic_payloads(state: &Value) -> Vec<(&'static str, String)> {
+    ["title", "artist", "album", "state", "elapsed", "art_url"]
+        .iter()
+        .map(|&name| {
+            let payload = match &state[name] {
+                Value::String(x) => x.clone(),
+                Value::Null => String::new(),
+                x => x.to_string(),
+            };
+            (name, payload)
+        })
+        .collect()
+}
+
+/// Home Assistant discovery messages for each sensor, along with their topics.
+fn discovery_configs(mqtt_config: &MqttConfig) -> Vec<(String, Value)> {
+    let prefix = &mqtt_config.discovery_prefix;
+    let id = &mqtt_config.client_id;
+    let topic = |name: &str| format!("{}/{}", mqtt_config.base_topic, name);
+    let device = json!({
+        "identifiers": [id],
+        "name": "mpdiscord",
+        "manufacturer": "mpdiscord",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    let sensors = [
+        (
+            "now_playing",
+            "Now playing",
+            "{{ value_json.title }}",
+            "mdi:music",
+        ),
+        (
+            "state",
+            "Player state",
+            "{{ value_json.state }}",
+            "mdi:play-pause",
+        ),
+    ];
+
+    sensors
+        .iter()
+        .map(|&(object_id, name, value_template, icon)| {
+            let config = json!({
+                "name": name,
+                "unique_id": format!("{}_{}", id, object_id),
+                "state_topic": topic("state"),
+                "value_template": value_template,
+                "json_attributes_topic": topic("state"),
+                "availability_topic": topic("availability"),
+                "icon": icon,
+                "device": device,
+            });
+
+            (
+                format!("{}/sensor/{}/{}/config", prefix, id, object_id),
+                config,
+            )
+        })
+        .collect()
+}
+
+pub async fn mqtt_updater(
+    config: &Config,
+    mqtt_config: &MqttConfig,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let availability = format!("{}/availability", mqtt_config.base_topic);
+
+    let mut options = MqttOptions::new(&mqtt_config.client_id, &mqtt_config.host, mqtt_config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        &availability,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (&mqtt_config.username, &mqtt_config.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    let publisher = Publisher {
+        client,
+        mqtt_config,
+    };
+
+    let mut last_state: Option<Value> = None;
+
+    loop {
+        tokio::select! {
+            song_status = safe_recv(&mut rx) => {
+                let state = now_playing(&song_status?, config);
+                debug!("publishing {}", state);
+                publisher.publish_state(&state).await?;
+                last_state = Some(state);
+            }
+            event = eventloop.poll() => {
+                if let Event::Incoming(Packet::ConnAck(_)) = event? {
+                    info!("connected to mqtt broker");
+
+                    publisher.publish(availability.clone(), "online").await?;
+
+                    if mqtt_config.discovery {
+                        publisher.publish_discovery().await?;
+                    }
+
+                    // Retained messages are normally kept by the broker, but it might have
+                    // restarted without persistence.
+                    if let Some(state) = &last_state {
+                        publisher.publish_state(state).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util;
+    use crate::mpd::test_util::playing;
+    use crate::mpd::Change;
+    use std::time::SystemTime;
+
+    #[test]
+    fn state_payload() {
+        let config = test_util::config(
+            "[web]\nlisten_addr = \"127.0.0.1:8000\"\npublic_addr = \"https://example.com\"",
+        );
+        let song_status = playing(5, "Song", 42.5, SystemTime::now()).with_change(Change::Seeked);
+        let state = now_playing(&song_status, &config);
+
+        assert_eq!(state["state"], "playing");
+        assert_eq!(state["title"], "Song");
+        assert_eq!(state["artist"], Value::Null);
+        assert_eq!(state["elapsed"], 42);
+        assert_eq!(state["duration"], 180);
+        assert_eq!(state["art_url"], "https://example.com/art/5");
+
+        let payloads = topic_payloads(&state);
+        assert!(payloads.contains(&("title", "Song".into())));
+        assert!(payloads.contains(&("artist", String::new())));
+        assert!(payloads.contains(&("elapsed", "42".into())));
+    }
+
+    #[test]
+    fn discovery() {
+        let config = test_util::config("[mqtt]\nhost = \"localhost\"\ndiscovery = true");
+        let configs = discovery_configs(config.mqtt.as_ref().unwrap());
+
+        let (topic, sensor) = &configs[0];
+        assert_eq!(topic, "homeassistant/sensor/mpdiscord/now_playing/config");
+        assert_eq!(sensor["unique_id"], "mpdiscord_now_playing");
+        assert_eq!(sensor["state_topic"], "mpdiscord/state");
+        assert_eq!(sensor["availability_topic"], "mpdiscord/availability");
+    }
+}
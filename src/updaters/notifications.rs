@@ -0,0 +1,168 @@
+use super::safe_recv;
+use crate::art_server::{art_reader, shrink_art};
+use crate::config::NotificationsConfig;
+use crate::conversions::get_artist;
+use crate::mpd::{Change, Mpd, SongStatus};
+use crate::notifications::NotificationsProxy;
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+use std::collections::HashMap;
+use tokio::task::spawn_blocking;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+/// Converts the cover to the raw `image-data` hint, as `(iiibiiay)`.
+async fn image_data(mpd: &Mpd, song_status: &SongStatus) -> Result<Option<Value<'static>>> {
+    let song_id = match song_status.song_id() {
+        Some(song_id) => song_id,
+        None => return Ok(None),
+    };
+
+    let (data, mime) = match mpd.song_art(song_id).await? {
+        Some(art) => art,
+        None => return Ok(None),
+    };
+
+    // Decoding a large cover takes long enough to hold up the other sinks.
+    let image = spawn_blocking(move || -> Result<_> {
+        Ok(shrink_art(art_reader(&data, mime.as_deref())?.decode()?).into_rgba8())
+    })
+    .await??;
+    let (width, height) = image.dimensions();
+
+    Ok(Some(Value::from((
+        width as i32,
+        height as i32,
+        (width * 4) as i32,
+        true,
+        8i32,
+        4i32,
+        image.into_raw(),
+    ))))
+}
+
+/// Escapes text for the body, which servers may interpret as a subset of HTML.
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn summary_and_body(song_status: &SongStatus) -> (String, String) {
+    let song = song_status.song.as_ref();
+    let title = song
+        .and_then(|x| x.title())
+        .or_else(|| song.map(|x| x.url.as_str()))
+        .unwrap_or("Nothing playing")
+        .to_string();
+
+    let mut body = song
+        .and_then(get_artist)
+        .unwrap_or_else(|| "Unknown Artist".to_string());
+    if let Some(album) = song.and_then(|x| x.album()) {
+        body.push_str(&format!("\n{}", album));
+    }
+
+    let body = escape_markup(&body);
+
+    match song_status.change {
+        Change::Paused => (format!("Paused: {}", title), body),
+        Change::Stopped => ("Stopped".to_string(), String::new()),
+        _ => (title, body),
+    }
+}
+
+pub async fn notifications_updater(
+    config: &NotificationsConfig,
+    mpd: &Mpd,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let connection = match &config.bus_address {
+        Some(address) => {
+            zbus::connection::Builder::address(address.as_str())?
+                .build()
+                .await?
+        }
+        None => Connection::session().await?,
+    };
+    let proxy = NotificationsProxy::new(&connection).await?;
+
+    let mut last_id = 0;
+
+    loop {
+        trace!("getting status");
+        let song_status = safe_recv(&mut rx).await?;
+
+        let wanted = match song_status.change {
+            Change::TrackChanged => song_status.song.is_some(),
+            Change::Paused => config.notify_pause,
+            Change::Stopped => config.notify_stop,
+            Change::Resumed | Change::Seeked => false,
+        };
+
+        if !wanted {
+            continue;
+        }
+
+        let (summary, body) = summary_and_body(&song_status);
+
+        let mut hints = HashMap::new();
+        if song_status.change != Change::Stopped {
+            match image_data(mpd, &song_status).await {
+                Ok(Some(image)) => {
+                    hints.insert("image-data", image);
+                }
+                Ok(None) => {}
+                Err(err) => warn!("couldn't load cover: {}", err),
+            }
+        }
+
+        debug!("notifying: {}", summary);
+        last_id = proxy
+            .notify(
+                "mpdiscord",
+                last_id,
+                "audio-x-generic",
+                &summary,
+                &body,
+                &[],
+                hints,
+                config.timeout_ms,
+            )
+            .await?;
+        info!("sent notification");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd::test_util::{playing, song};
+    use std::time::SystemTime;
+
+    #[test]
+    fn body_is_escaped() {
+        let mut song_status =
+            playing(1, "<b>Title</b>", 0.0, SystemTime::now()).with_change(Change::TrackChanged);
+        song_status.song = Some(song(
+            "file: a.flac\nTitle: <b>Title</b>\nArtist: Simon & Garfunkel\n\
+             Album: <i>Album</i>\nPos: 0\nId: 1\n",
+        ));
+
+        let (summary, body) = summary_and_body(&song_status);
+        assert_eq!(summary, "<b>Title</b>");
+        assert_eq!(body, "Simon &amp; Garfunkel\n&lt;i&gt;Album&lt;/i&gt;");
+    }
+
+    #[test]
+    fn paused_and_stopped() {
+        let song_status = playing(1, "Title", 0.0, SystemTime::now());
+
+        let (summary, _) = summary_and_body(&song_status.clone().with_change(Change::Paused));
+        assert_eq!(summary, "Paused: Title");
+
+        let stopped = summary_and_body(&song_status.with_change(Change::Stopped));
+        assert_eq!(stopped, ("Stopped".to_string(), String::new()));
+    }
+}
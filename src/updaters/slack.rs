@@ -0,0 +1,45 @@
+use super::Throttle;
+use crate::config::{Config, SlackConfig};
+use crate::conversions;
+use crate::mpd::SongStatus;
+use crate::slack::{Slack, MAX_STATUS_TEXT};
+use crate::StatusRx;
+use anyhow::Result;
+use log::*;
+use std::time::UNIX_EPOCH;
+
+/// When Slack should clear the status, as a Unix timestamp.
+fn expiration(slack_config: &SlackConfig, song_status: &SongStatus) -> Result<u64> {
+    let expires_at = conversions::track_end(song_status) + slack_config.expire_after();
+    Ok(expires_at.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+pub async fn slack_updater(
+    config: &Config,
+    slack_config: &SlackConfig,
+    mut rx: StatusRx,
+) -> Result<!> {
+    let slack = Slack::new(slack_config);
+    let mut throttle = Throttle::new(slack_config.rate_limit);
+
+    loop {
+        trace!("getting status");
+        let song_status = throttle.recv(&mut rx).await?;
+
+        let template = slack_config.template.as_deref();
+        let text = conversions::status_text(template, &song_status, config, MAX_STATUS_TEXT);
+
+        if let Some(text) = text {
+            debug!("setting status: {}", text);
+            let expiration = expiration(slack_config, &song_status)?;
+            slack
+                .set_status(&text, &slack_config.emoji, expiration)
+                .await?;
+            info!("set status");
+        } else {
+            debug!("clearing status");
+            slack.clear_status().await?;
+            info!("cleared status");
+        }
+    }
+}
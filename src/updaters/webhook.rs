@@ -0,0 +1,142 @@
+use super::safe_recv;
+use crate::config::{Config, WebhookConfig};
+use crate::conversions::get_vars;
+use crate::template;
+use crate::StatusRx;
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use log::*;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const DEFAULT_TEMPLATE: &str = r#"{{
+    "change": "{change}",
+    "state": "{state}",
+    "title": "{title}",
+    "artist": "{artist}",
+    "album": "{album}",
+    "file": "{file}",
+    "elapsed": "{elapsed}",
+    "duration": "{duration}",
+    "art_url": "{art_url}",
+    "text": "{text}"
+}}"#;
+
+const SIGNATURE_HEADER: &str = "X-Mpdiscord-Signature";
+
+/// Escapes a value to be placed between quotes in a JSON template.
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn send(client: &Client, hook: &WebhookConfig, body: &str) -> Result<()> {
+    let mut request = client
+        .post(&hook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+
+    for (name, value) in &hook.headers {
+        request = request.header(name, value);
+    }
+
+    if let Some(secret) = &hook.secret {
+        request = request.header(SIGNATURE_HEADER, sign(secret, body));
+    }
+
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Sends the body, retrying with exponential backoff.
+async fn deliver(client: &Client, hook: &WebhookConfig, body: &str) -> Result<()> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+
+    loop {
+        match send(client, hook, body).await {
+            Ok(()) => break Ok(()),
+            Err(err) if attempt < hook.retries => {
+                debug!("delivery to {} failed, retrying: {}", hook.url, err);
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => break Err(err),
+        }
+    }
+}
+
+/// Delivers changes to the `index`th configured webhook.
+pub async fn webhook_updater(config: Arc<Config>, index: usize, mut rx: StatusRx) -> Result<!> {
+    let hook = &config.webhook[index];
+    let client = Client::builder().timeout(hook.timeout()).build()?;
+
+    let mut failures = 0;
+    let mut disabled_until: Option<Instant> = None;
+
+    loop {
+        trace!("getting status");
+        let song_status = safe_recv(&mut rx).await?;
+
+        if !hook.changes.contains(&song_status.change) {
+            continue;
+        }
+
+        if let Some(until) = disabled_until {
+            if Instant::now() < until {
+                debug!(
+                    "{} is disabled, dropping {:?}",
+                    hook.url, song_status.change
+                );
+                continue;
+            }
+
+            info!("re-enabling {}", hook.url);
+            disabled_until = None;
+        }
+
+        let vars = get_vars(&song_status, &config);
+        let template = hook.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        let body = template::render_with(template, &vars, json_escape);
+
+        if let Err(err) = serde_json::from_str::<serde_json::Value>(&body) {
+            warn!(
+                "template for {} doesn't produce valid JSON, not sending it: {}",
+                hook.url, err
+            );
+            continue;
+        }
+
+        match deliver(&client, hook, &body).await {
+            Ok(()) => {
+                info!("delivered {:?} to {}", song_status.change, hook.url);
+                failures = 0;
+            }
+            Err(err) => {
+                warn!("couldn't deliver to {}: {}", hook.url, err);
+                failures += 1;
+
+                if failures >= hook.max_failures {
+                    warn!(
+                        "{} failed {} times in a row, disabling it for {} minutes",
+                        hook.url, failures, hook.disable_minutes
+                    );
+                    disabled_until = Some(Instant::now() + hook.disable_for());
+                    failures = 0;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use super::Throttle;
+use crate::config::XmppConfig;
+use crate::mpd::{Change, PlayState};
+use crate::xmpp::{publish_tune, Tune, NS_CLIENT};
+use crate::StatusRx;
+use anyhow::{bail, Result};
+use futures_util::StreamExt;
+use log::*;
+use tokio_xmpp::parsers::jid::Jid;
+use tokio_xmpp::{AsyncClient, Event};
+
+pub async fn xmpp_updater(xmpp_config: &XmppConfig, mut rx: StatusRx) -> Result<!> {
+    let jid: Jid = xmpp_config.jid.parse()?;
+    let mut client = AsyncClient::new(jid, xmpp_config.password.clone());
+    // Reconnecting is left to the supervisor, like every other updater.
+    client.set_reconnect(false);
+
+    let mut throttle = Throttle::new(xmpp_config.rate_limit);
+    let mut online = false;
+    let mut next_id: u64 = 0;
+
+    loop {
+        tokio::select! {
+            event = client.next() => match event {
+                Some(Event::Online { bound_jid, .. }) => {
+                    info!("logged in as {}", bound_jid);
+                    online = true;
+                }
+                Some(Event::Disconnected(err)) => bail!(err),
+                Some(Event::Stanza(stanza)) => {
+                    if stanza.is("iq", NS_CLIENT) && stanza.attr("type") == Some("error") {
+                        warn!("publishing tune failed: {:?}", stanza);
+                    }
+                }
+                None => bail!("connection closed"),
+            },
+            song_status = throttle.recv(&mut rx), if online => {
+                let song_status = song_status?;
+
+                let tune = if song_status.status.state == PlayState::Stopped {
+                    Tune::default()
+                } else if song_status.change == Change::TrackChanged {
+                    song_status.song.as_ref().map(Tune::from_song).unwrap_or_default()
+                } else {
+                    debug!("ignoring {:?}", song_status.change);
+                    continue;
+                };
+
+                debug!("publishing: {:?}", tune.title);
+                let id = format!("mpdiscord-{}", next_id);
+                next_id += 1;
+                client.send_stanza(publish_tune(&id, &tune)).await?;
+                info!("published tune");
+            }
+        }
+    }
+}
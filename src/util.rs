@@ -0,0 +1,28 @@
+use anyhow::Result;
+use std::path::Path;
+use tokio::fs;
+
+/// Writes a file by renaming a temporary file over it, so readers never see it half-written.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+
+    fs::write(&temp, data).await?;
+    fs::rename(&temp, path).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test_util {
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    /// Serves `app` on a free local port, returning its base URL.
+    pub async fn mock_server(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+}
@@ -0,0 +1,131 @@
+use super::conversions::get_artist;
+use super::mpd::Song;
+use mpd_client::tag::Tag;
+use tokio_xmpp::parsers::minidom::Element;
+
+pub const NS_CLIENT: &str = "jabber:client";
+const NS_PUBSUB: &str = "http://jabber.org/protocol/pubsub";
+const NS_TUNE: &str = "http://jabber.org/protocol/tune";
+
+/// A XEP-0118 User Tune. Publishing one with every field empty says nothing is playing.
+#[derive(Default)]
+pub struct Tune {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub source: Option<String>,
+    pub track: Option<String>,
+    pub length: Option<u64>,
+    pub uri: Option<String>,
+}
+
+impl Tune {
+    pub fn from_song(song: &Song) -> Self {
+        let track = song
+            .tags
+            .get(&Tag::Track)
+            .and_then(|x| x.first())
+            .and_then(|x| x.split('/').next())
+            .map(str::to_string);
+
+        Self {
+            artist: get_artist(song),
+            title: song.title().map(str::to_string),
+            source: song.album().map(str::to_string),
+            track,
+            length: song.duration.map(|x| x.as_secs()),
+            // Only streams have something worth linking to.
+            uri: song.url.contains("://").then(|| song.url.clone()),
+        }
+    }
+
+    fn to_element(&self) -> Element {
+        let fields = [
+            ("artist", self.artist.clone()),
+            ("length", self.length.map(|x| x.to_string())),
+            ("source", self.source.clone()),
+            ("title", self.title.clone()),
+            ("track", self.track.clone()),
+            ("uri", self.uri.clone()),
+        ];
+
+        let mut tune = Element::builder("tune", NS_TUNE);
+        for (name, value) in fields {
+            if let Some(value) = value {
+                tune = tune.append(Element::builder(name, NS_TUNE).append(value).build());
+            }
+        }
+        tune.build()
+    }
+}
+
+/// Builds the `<iq/>` publishing the tune to our own PEP node.
+pub fn publish_tune(id: &str, tune: &Tune) -> Element {
+    let item = Element::builder("item", NS_PUBSUB)
+        .append(tune.to_element())
+        .build();
+    let publish = Element::builder("publish", NS_PUBSUB)
+        .attr("node", NS_TUNE)
+        .append(item)
+        .build();
+    let pubsub = Element::builder("pubsub", NS_PUBSUB)
+        .append(publish)
+        .build();
+
+    Element::builder("iq", NS_CLIENT)
+        .attr("type", "set")
+        .attr("id", id)
+        .append(pubsub)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd::test_util::song;
+
+    fn tune_of(iq: &Element) -> &Element {
+        iq.get_child("pubsub", NS_PUBSUB)
+            .and_then(|x| x.get_child("publish", NS_PUBSUB))
+            .and_then(|x| x.get_child("item", NS_PUBSUB))
+            .and_then(|x| x.get_child("tune", NS_TUNE))
+            .unwrap()
+    }
+
+    #[test]
+    fn tune_from_song() {
+        let song = song(
+            "file: http://radio.example.com/stream\nArtist: Artist\nTitle: Title\n\
+             Album: Album\nTrack: 3/12\nduration: 61.500\nPos: 0\nId: 1\n",
+        );
+        let tune = Tune::from_song(&song);
+
+        assert_eq!(tune.track.as_deref(), Some("3"));
+        assert_eq!(tune.length, Some(61));
+        assert_eq!(tune.uri.as_deref(), Some("http://radio.example.com/stream"));
+    }
+
+    #[test]
+    fn publishes_tune() {
+        let song = song("file: a.flac\nArtist: Artist\nTitle: Title\nPos: 0\nId: 1\n");
+        let iq = publish_tune("mpdiscord-0", &Tune::from_song(&song));
+
+        assert!(iq.is("iq", NS_CLIENT));
+        assert_eq!(iq.attr("type"), Some("set"));
+        assert_eq!(iq.attr("id"), Some("mpdiscord-0"));
+
+        let publish = iq.get_child("pubsub", NS_PUBSUB).unwrap();
+        let publish = publish.get_child("publish", NS_PUBSUB).unwrap();
+        assert_eq!(publish.attr("node"), Some(NS_TUNE));
+
+        let tune = tune_of(&iq);
+        assert_eq!(tune.get_child("artist", NS_TUNE).unwrap().text(), "Artist");
+        assert_eq!(tune.get_child("title", NS_TUNE).unwrap().text(), "Title");
+        assert!(tune.get_child("uri", NS_TUNE).is_none());
+    }
+
+    #[test]
+    fn empty_tune_means_stopped() {
+        let iq = publish_tune("mpdiscord-1", &Tune::default());
+        assert_eq!(tune_of(&iq).children().count(), 0);
+    }
+}